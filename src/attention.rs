@@ -0,0 +1,170 @@
+#[cfg(not(target_os = "macos"))]
+use log::debug;
+use skulpin::sdl2;
+
+// SDL_syswm.h isn't part of the generated sdl2 bindings, so the parts of it needed to find the
+// native window handle are declared here. The union of platform handles is padded to 64 bytes.
+#[cfg(any(target_os = "windows", all(unix, not(target_os = "macos"))))]
+mod syswm {
+    use std::os::raw::c_int;
+    use skulpin::sdl2;
+    use skulpin::sdl2::sys::{SDL_bool, SDL_version, SDL_Window, SDL_GetVersion};
+
+    #[cfg(target_os = "windows")]
+    pub const SDL_SYSWM_WINDOWS: c_int = 1;
+    #[cfg(not(target_os = "windows"))]
+    pub const SDL_SYSWM_X11: c_int = 2;
+
+    #[cfg(target_os = "windows")]
+    #[derive(Clone, Copy)]
+    #[repr(C)]
+    pub struct WindowsHandles {
+        pub window: winapi::shared::windef::HWND
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    #[derive(Clone, Copy)]
+    #[repr(C)]
+    pub struct X11Handles {
+        pub display: *mut x11_dl::xlib::Display,
+        pub window: x11_dl::xlib::Window
+    }
+
+    #[repr(C)]
+    pub union Handles {
+        #[cfg(target_os = "windows")]
+        pub windows: WindowsHandles,
+        #[cfg(not(target_os = "windows"))]
+        pub x11: X11Handles,
+        _padding: [u8; 64]
+    }
+
+    #[repr(C)]
+    pub struct SysWmInfo {
+        pub version: SDL_version,
+        pub subsystem: c_int,
+        pub info: Handles
+    }
+
+    extern "C" {
+        fn SDL_GetWindowWMInfo(window: *mut SDL_Window, info: *mut SysWmInfo) -> SDL_bool;
+    }
+
+    // SDL only fills in the fields known to the version it is told about, so the version linked
+    // against is passed along.
+    pub fn window_info(window: &sdl2::video::Window) -> Option<SysWmInfo> {
+        unsafe {
+            let mut info: SysWmInfo = std::mem::zeroed();
+            SDL_GetVersion(&mut info.version);
+            if SDL_GetWindowWMInfo(window.raw(), &mut info) == SDL_bool::SDL_TRUE {
+                Some(info)
+            } else {
+                None
+            }
+        }
+    }
+}
+
+// Asks the OS to point the user back at the window, by flashing it in the taskbar until it is
+// focused again.
+#[cfg(target_os = "windows")]
+pub fn request_attention(window: &sdl2::video::Window) {
+    use winapi::um::winuser::{FlashWindowEx, FLASHWINFO, FLASHW_TRAY, FLASHW_TIMERNOFG};
+
+    match syswm::window_info(window) {
+        Some(info) if info.subsystem == syswm::SDL_SYSWM_WINDOWS => {
+            let mut flash_info = FLASHWINFO {
+                cbSize: std::mem::size_of::<FLASHWINFO>() as u32,
+                hwnd: unsafe { info.info.windows.window },
+                dwFlags: FLASHW_TRAY | FLASHW_TIMERNOFG,
+                uCount: 0,
+                dwTimeout: 0
+            };
+            unsafe {
+                FlashWindowEx(&mut flash_info);
+            }
+        },
+        _ => debug!("Attention requested, but the native window couldn't be found")
+    }
+}
+
+// Flashing stops by itself once the window is focused.
+#[cfg(target_os = "windows")]
+pub fn clear_attention_request(_window: &sdl2::video::Window) {}
+
+// Asks the OS to point the user back at the window by setting the urgency hint, which window
+// managers show in their taskbars or by marking the window's workspace.
+#[cfg(all(unix, not(target_os = "macos")))]
+pub fn request_attention(window: &sdl2::video::Window) {
+    set_urgency_hint(window, true);
+}
+
+// Unlike the other platforms, the urgency hint stays set until the client clears it.
+#[cfg(all(unix, not(target_os = "macos")))]
+pub fn clear_attention_request(window: &sdl2::video::Window) {
+    set_urgency_hint(window, false);
+}
+
+// Xlib is loaded at runtime so that Neovide still starts on systems without it.
+#[cfg(all(unix, not(target_os = "macos")))]
+fn set_urgency_hint(window: &sdl2::video::Window, urgent: bool) {
+    use std::os::raw::c_void;
+    use x11_dl::xlib::{Xlib, XUrgencyHint};
+
+    let handles = match syswm::window_info(window) {
+        Some(info) if info.subsystem == syswm::SDL_SYSWM_X11 => unsafe { info.info.x11 },
+        // Wayland has no equivalent to the urgency hint.
+        _ => {
+            debug!("Attention requested, but the window isn't an X11 window");
+            return;
+        }
+    };
+
+    let xlib = match Xlib::open() {
+        Ok(xlib) => xlib,
+        Err(error) => {
+            debug!("Could not load Xlib to set the urgency hint: {}", error);
+            return;
+        }
+    };
+
+    unsafe {
+        let mut hints = (xlib.XGetWMHints)(handles.display, handles.window);
+        if hints.is_null() {
+            hints = (xlib.XAllocWMHints)();
+            if hints.is_null() {
+                return;
+            }
+        }
+
+        if urgent {
+            (*hints).flags |= XUrgencyHint;
+        } else {
+            (*hints).flags &= !XUrgencyHint;
+        }
+
+        (xlib.XSetWMHints)(handles.display, handles.window, hints);
+        (xlib.XFree)(hints as *mut c_void);
+        (xlib.XFlush)(handles.display);
+    }
+}
+
+// Asks the OS to point the user back at the app by bouncing its dock icon. The request applies
+// to the whole app, so the window isn't needed.
+#[cfg(target_os = "macos")]
+pub fn request_attention(_window: &sdl2::video::Window) {
+    use objc::{class, msg_send, sel, sel_impl};
+    use objc::runtime::Object;
+
+    // Bounces the icon once rather than until the app is activated.
+    const NS_INFORMATIONAL_REQUEST: isize = 10;
+
+    unsafe {
+        let application: *mut Object = msg_send![class!(NSApplication), sharedApplication];
+        let _: isize = msg_send![application, requestUserAttention: NS_INFORMATIONAL_REQUEST];
+    }
+}
+
+// macOS cancels the request itself when the app is activated.
+#[cfg(target_os = "macos")]
+pub fn clear_attention_request(_window: &sdl2::video::Window) {}
@@ -0,0 +1,253 @@
+use std::sync::mpsc::{channel, Sender, Receiver};
+
+use rmpv::Value;
+use nvim_rs::Neovim;
+use nvim_rs::error::CallError;
+use parking_lot::Mutex;
+use skulpin::sdl2::clipboard::ClipboardUtil;
+use tokio::sync::oneshot;
+use log::{error, trace};
+
+use crate::redraw_scheduler::REDRAW_SCHEDULER;
+use super::NeovimWriter;
+
+// Register contents as neovim's clipboard provider interface deals in them: a list of lines
+// along with a register type, which is "V" for whole lines and "v" for anything else.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClipboardContents {
+    pub lines: Vec<String>,
+    pub register_type: String
+}
+
+impl ClipboardContents {
+    // Text ending in a newline was most likely copied as whole lines.
+    fn from_text(text: &str) -> ClipboardContents {
+        let text = text.replace("\r\n", "\n");
+        let (text, register_type) = if text.ends_with('\n') {
+            (&text[..text.len() - 1], "V")
+        } else {
+            (&text[..], "v")
+        };
+
+        ClipboardContents {
+            lines: text.split('\n').map(String::from).collect(),
+            register_type: register_type.to_string()
+        }
+    }
+
+    fn to_text(&self) -> String {
+        let mut text = self.lines.join("\n");
+        if self.register_type == "V" {
+            text.push('\n');
+        }
+        text
+    }
+
+    pub fn parse(lines: &Value, register_type: Option<&Value>) -> Option<ClipboardContents> {
+        let lines = lines.as_array()?
+            .iter()
+            .map(|line| line.as_str().map(String::from))
+            .collect::<Option<Vec<String>>>()?;
+        let register_type = register_type
+            .and_then(Value::as_str)
+            .unwrap_or("v")
+            .to_string();
+
+        Some(ClipboardContents { lines, register_type })
+    }
+
+    pub fn into_value(self) -> Value {
+        Value::Array(vec![
+            Value::Array(self.lines.into_iter().map(Value::from).collect()),
+            Value::from(self.register_type)
+        ])
+    }
+}
+
+enum ClipboardRequest {
+    Get { response: oneshot::Sender<ClipboardContents> },
+    Set { contents: ClipboardContents }
+}
+
+// SDL's clipboard may only be touched from the thread which initialized video, so requests from
+// neovim are queued here for the ui loop to answer.
+lazy_static! {
+    static ref CLIPBOARD_REQUESTS: (Mutex<Sender<ClipboardRequest>>, Mutex<Receiver<ClipboardRequest>>) = {
+        let (sender, receiver) = channel();
+        (Mutex::new(sender), Mutex::new(receiver))
+    };
+}
+
+fn queue_request(request: ClipboardRequest) {
+    let (sender, _) = &*CLIPBOARD_REQUESTS;
+    if sender.lock().send(request).is_err() {
+        error!("Could not queue clipboard request");
+    }
+    REDRAW_SCHEDULER.wake();
+}
+
+pub async fn get_clipboard() -> Option<ClipboardContents> {
+    let (response, receiver) = oneshot::channel();
+    queue_request(ClipboardRequest::Get { response });
+    receiver.await.ok()
+}
+
+pub fn set_clipboard(contents: ClipboardContents) {
+    queue_request(ClipboardRequest::Set { contents });
+}
+
+// A program neovim's own provider would use for the X11 or Wayland primary selection, along with
+// the environment variable telling whether that display server is running.
+struct PrimarySelectionTool {
+    display: &'static str,
+    copy: &'static [&'static str],
+    paste: &'static [&'static str]
+}
+
+// In the order neovim's provider tries them. SDL can only reach the clipboard, so the * register
+// goes through one of these instead.
+const PRIMARY_SELECTION_TOOLS: &[PrimarySelectionTool] = &[
+    PrimarySelectionTool {
+        display: "$WAYLAND_DISPLAY",
+        copy: &["wl-copy", "--foreground", "--primary", "--type", "text/plain"],
+        paste: &["wl-paste", "--no-newline", "--primary"]
+    },
+    PrimarySelectionTool {
+        display: "$DISPLAY",
+        copy: &["xclip", "-quiet", "-i", "-selection", "primary"],
+        paste: &["xclip", "-o", "-selection", "primary"]
+    },
+    PrimarySelectionTool {
+        display: "$DISPLAY",
+        copy: &["xsel", "--nodetach", "-i", "-p"],
+        paste: &["xsel", "-o", "-p"]
+    }
+];
+
+fn vim_list(arguments: &[&str]) -> String {
+    let items: Vec<String> = arguments.iter().map(|argument| format!("'{}'", argument)).collect();
+    format!("[{}]", items.join(", "))
+}
+
+// Builds an expression which picks the command of the first primary selection tool available when
+// the provider is loaded. Without one, * shares the clipboard like it does on Windows and macOS.
+fn primary_selection_expression(command: fn(&PrimarySelectionTool) -> &'static [&'static str], fallback: String) -> String {
+    PRIMARY_SELECTION_TOOLS.iter().rev().fold(fallback, |expression, tool| format!(
+        "exists('{}') && executable('{}') && executable('{}') ? {} : {}",
+        tool.display, tool.copy[0], tool.paste[0], vim_list(command(tool)), expression))
+}
+
+// Points neovim's clipboard provider at us unless the user already configured one. One we set up
+// for an earlier connection is replaced since its channel is gone. The provider picks g:clipboard
+// up when it is loaded, so it is reloaded in case something used the clipboard before we attached.
+pub async fn register_clipboard_provider<W: NeovimWriter>(nvim: &Neovim<W>, channel: u64) -> Result<(), Box<CallError>> {
+    let copy = format!(
+        "{{ lines, regtype -> rpcrequest({}, 'neovide.set_clipboard', lines, regtype) }}", channel);
+    let paste = format!("{{ -> rpcrequest({}, 'neovide.get_clipboard') }}", channel);
+    let copy_primary = primary_selection_expression(|tool| tool.copy, copy.clone());
+    let paste_primary = primary_selection_expression(|tool| tool.paste, paste.clone());
+
+    nvim.command(&format!(
+        "if !exists('g:clipboard') || get(g:clipboard, 'name', '') ==# 'neovide' | let g:clipboard = {{ 'name': 'neovide', 'copy': {{ '+': {}, '*': {} }}, 'paste': {{ '+': {}, '*': {} }}, 'cache_enabled': 0 }} | unlet! g:loaded_clipboard_provider | runtime autoload/provider/clipboard.vim | endif",
+        copy, copy_primary, paste, paste_primary)).await
+}
+
+// Answers the clipboard requests queued since the last call.
+pub fn handle_clipboard_requests(clipboard: &ClipboardUtil) {
+    let (_, receiver) = &*CLIPBOARD_REQUESTS;
+    let receiver = receiver.lock();
+    while let Ok(request) = receiver.try_recv() {
+        match request {
+            ClipboardRequest::Get { response } => {
+                trace!("Clipboard read");
+                let text = clipboard.clipboard_text().unwrap_or_else(|error| {
+                    error!("Could not read the clipboard: {}", error);
+                    String::new()
+                });
+                response.send(ClipboardContents::from_text(&text)).ok();
+            },
+            ClipboardRequest::Set { contents } => {
+                trace!("Clipboard written");
+                if let Err(error) = clipboard.set_clipboard_text(&contents.to_text()) {
+                    error!("Could not write to the clipboard: {}", error);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn contents(lines: &[&str], register_type: &str) -> ClipboardContents {
+        ClipboardContents {
+            lines: lines.iter().map(|line| line.to_string()).collect(),
+            register_type: register_type.to_string()
+        }
+    }
+
+    #[test]
+    fn text_is_split_into_lines() {
+        assert_eq!(ClipboardContents::from_text("one\ntwo"), contents(&["one", "two"], "v"));
+        assert_eq!(ClipboardContents::from_text("one\r\ntwo"), contents(&["one", "two"], "v"));
+    }
+
+    #[test]
+    fn trailing_newline_means_whole_lines() {
+        assert_eq!(ClipboardContents::from_text("one\ntwo\n"), contents(&["one", "two"], "V"));
+        assert_eq!(ClipboardContents::from_text("one\r\n"), contents(&["one"], "V"));
+        assert_eq!(ClipboardContents::from_text("\n"), contents(&[""], "V"));
+    }
+
+    #[test]
+    fn empty_text_is_one_empty_line() {
+        assert_eq!(ClipboardContents::from_text(""), contents(&[""], "v"));
+        assert_eq!(contents(&[""], "v").to_text(), "");
+    }
+
+    #[test]
+    fn text_round_trips() {
+        for text in &["one", "one\ntwo", "one\ntwo\n", "\n\n", ""] {
+            assert_eq!(ClipboardContents::from_text(text).to_text(), *text);
+        }
+        assert_eq!(ClipboardContents::from_text("one\r\ntwo\r\n").to_text(), "one\ntwo\n");
+    }
+
+    #[test]
+    fn block_selections_keep_their_lines() {
+        assert_eq!(contents(&["ab", "cd"], "\u{16}2").to_text(), "ab\ncd");
+    }
+
+    #[test]
+    fn parses_provider_arguments() {
+        let lines = Value::Array(vec![Value::from("one"), Value::from("two")]);
+        assert_eq!(ClipboardContents::parse(&lines, Some(&Value::from("V"))), Some(contents(&["one", "two"], "V")));
+        assert_eq!(ClipboardContents::parse(&lines, None), Some(contents(&["one", "two"], "v")));
+        assert_eq!(ClipboardContents::parse(&lines, Some(&Value::Nil)), Some(contents(&["one", "two"], "v")));
+        assert_eq!(ClipboardContents::parse(&Value::Array(Vec::new()), None), Some(contents(&[], "v")));
+    }
+
+    #[test]
+    fn rejects_lines_which_arent_strings() {
+        assert_eq!(ClipboardContents::parse(&Value::from("one"), None), None);
+        assert_eq!(ClipboardContents::parse(&Value::Array(vec![Value::from("one"), Value::from(2)]), None), None);
+    }
+
+    #[test]
+    fn into_value_matches_the_provider_interface() {
+        let value = contents(&["one"], "V").into_value();
+        assert_eq!(value, Value::Array(vec![Value::Array(vec![Value::from("one")]), Value::from("V")]));
+    }
+
+    #[test]
+    fn primary_selection_tools_are_tried_in_order_before_the_clipboard() {
+        let expression = primary_selection_expression(|tool| tool.paste, "FALLBACK".to_string());
+        let wayland = expression.find("'wl-paste', '--no-newline', '--primary'").unwrap();
+        let xclip = expression.find("'xclip', '-o', '-selection', 'primary'").unwrap();
+        let xsel = expression.find("'xsel', '-o', '-p'").unwrap();
+        assert!(wayland < xclip && xclip < xsel);
+        assert!(expression.ends_with(" : FALLBACK"));
+        assert!(expression.starts_with("exists('$WAYLAND_DISPLAY') && executable('wl-copy') && executable('wl-paste') ? "));
+    }
+}
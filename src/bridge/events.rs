@@ -1,14 +1,17 @@
 use std::error;
 use std::fmt;
 use std::convert::TryInto;
+use std::sync::atomic::{AtomicU64, Ordering};
 
 use rmpv::Value;
 use skulpin::skia_safe::Color4f;
+use log::warn;
 
 use crate::editor::EDITOR;
-use crate::error_handling::ResultPanicExplanation;
 use crate::editor::{Colors, Style, CursorMode, CursorShape};
 
+static SKIPPED_EVENT_COUNT: AtomicU64 = AtomicU64::new(0);
+
 #[derive(Debug, Clone)]
 pub enum EventParseError {
     InvalidArray(Value),
@@ -16,9 +19,14 @@ pub enum EventParseError {
     InvalidString(Value),
     InvalidU64(Value),
     InvalidI64(Value),
+    InvalidF64(Value),
     InvalidBool(Value),
+    InvalidWindowHandle(Value),
     InvalidWindowAnchor(Value),
-    InvalidEventFormat
+    InvalidEventFormat,
+    // Wraps an error with the name of the event it came from and the index of the failing argument
+    // list within that event's batch.
+    InvalidEventArguments { event_name: String, argument_index: usize, error: Box<EventParseError> }
 }
 type Result<T> = std::result::Result<T, EventParseError>;
 
@@ -30,16 +38,23 @@ impl fmt::Display for EventParseError {
             EventParseError::InvalidString(value) => write!(f, "invalid string format {}", value),
             EventParseError::InvalidU64(value) => write!(f, "invalid u64 format {}", value),
             EventParseError::InvalidI64(value) => write!(f, "invalid i64 format {}", value),
+            EventParseError::InvalidF64(value) => write!(f, "invalid f64 format {}", value),
             EventParseError::InvalidBool(value) => write!(f, "invalid bool format {}", value),
+            EventParseError::InvalidWindowHandle(value) => write!(f, "invalid window handle format {}", value),
             EventParseError::InvalidWindowAnchor(value) => write!(f, "invalid window anchor format {}", value),
-            EventParseError::InvalidEventFormat => write!(f, "invalid event format")
+            EventParseError::InvalidEventFormat => write!(f, "invalid event format"),
+            EventParseError::InvalidEventArguments { event_name, argument_index, error } => 
+                write!(f, "invalid arguments at index {} of {} event: {}", argument_index, event_name, error)
         }
     }
 }
 
 impl error::Error for EventParseError {
     fn source(&self) -> Option<&(dyn error::Error + 'static)> {
-        None
+        match self {
+            EventParseError::InvalidEventArguments { error, .. } => Some(error.as_ref()),
+            _ => None
+        }
     }
 }
 
@@ -52,7 +67,7 @@ pub struct GridLineCell {
 
 pub type StyledContent = Vec<(u64, String)>;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum MessageKind {
     Unknown,
     Confirm,
@@ -87,6 +102,13 @@ impl MessageKind {
             _ => MessageKind::Unknown
         }
     }
+
+    pub fn is_error(&self) -> bool {
+        match self {
+            MessageKind::Error | MessageKind::EchoError | MessageKind::LuaError | MessageKind::RpcError => true,
+            _ => false
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -104,6 +126,22 @@ pub enum GuiOption {
     Unknown(String, Value)
 }
 
+#[derive(Debug, Clone)]
+pub struct PopupMenuItem {
+    pub word: String,
+    pub kind: String,
+    pub menu: String,
+    pub info: String
+}
+
+// Tabpage handles are kept as the raw msgpack extension values since that is what the api
+// expects to be handed back when switching to or closing a tab.
+#[derive(Debug, Clone)]
+pub struct TabInfo {
+    pub tab: Value,
+    pub name: String
+}
+
 #[derive(Debug)]
 pub enum WindowAnchor {
     NorthWest,
@@ -117,22 +155,26 @@ pub enum RedrawEvent {
     SetTitle { title: String },
     ModeInfoSet { cursor_modes: Vec<CursorMode> },
     OptionSet { gui_option: GuiOption },
-    ModeChange { mode_index: u64 },
+    ModeChange { mode: String, mode_index: u64 },
     BusyStart,
     BusyStop,
+    Bell,
+    VisualBell,
     Flush,
     Resize { grid: u64, width: u64, height: u64 },
     DefaultColorsSet { colors: Colors },
     HighlightAttributesDefine { id: u64, style: Style },
+    HighlightGroupSet { name: String, id: u64 },
     GridLine { grid: u64, row: u64, column_start: u64, cells: Vec<GridLineCell> },
     Clear { grid: u64 },
     CursorGoto { grid: u64, row: u64, column: u64 },
     Scroll { grid: u64, top: u64, bottom: u64, left: u64, right: u64, rows: i64, columns: i64 },
     WindowPosition { grid: u64, window: u64, start_row: u64, start_column: u64, width: u64, height: u64 },
-    WindowFloatPosition { grid: u64, window: u64, anchor: WindowAnchor, anchor_grid: u64, anchor_row: u64, anchor_column: u64, focusable: bool },
+    WindowFloatPosition { grid: u64, window: u64, anchor: WindowAnchor, anchor_grid: u64, anchor_row: f64, anchor_column: f64, focusable: bool },
     WindowExternalPosition { grid: u64, window: u64 },
     WindowHide { grid: u64 },
     WindowClose { grid: u64 },
+    GridDestroy { grid: u64 },
     MessageSetPosition { grid: u64, row: u64, scrolled: bool, separator_character: String },
     CommandLineShow { content: StyledContent, position: u64, first_character: String, prompt: String, indent: u64, level: u64 },
     CommandLinePosition { position: u64, level: u64 },
@@ -146,7 +188,13 @@ pub enum RedrawEvent {
     MessageShowMode { content: StyledContent },
     MessageShowCommand { content: StyledContent },
     MessageRuler { content: StyledContent },
-    MessageHistoryShow { entries: Vec<(MessageKind, StyledContent)>}
+    MessageHistoryShow { entries: Vec<(MessageKind, StyledContent)>},
+    // Grid is -1 when the menu belongs to the external command line, in which case column is a
+    // position within the command line instead of the grid.
+    PopupMenuShow { items: Vec<PopupMenuItem>, selected: Option<u64>, row: u64, column: u64, grid: i64 },
+    PopupMenuSelect { selected: Option<u64> },
+    PopupMenuHide,
+    TablineUpdate { current_tab: Value, tabs: Vec<TabInfo> }
 }
 
 fn unpack_color(packed_color: u64) -> Color4f {
@@ -162,13 +210,15 @@ fn unpack_color(packed_color: u64) -> Color4f {
     }
 }
 
+// Newer versions of neovim add arguments to the end of existing events, so any values past the
+// ones we know about are ignored.
 fn extract_values<Arr: AsMut<[Value]>>(values: Vec<Value>, mut arr: Arr) -> Result<Arr> {
     let arr_ref = arr.as_mut();
 
-    if values.len() != arr_ref.len() {
+    if values.len() < arr_ref.len() {
         Err(EventParseError::InvalidEventFormat)
     } else {
-        for (i, val) in values.into_iter().enumerate() {
+        for (i, val) in values.into_iter().take(arr_ref.len()).enumerate() {
             arr_ref[i] = val;
         }
 
@@ -196,6 +246,27 @@ fn parse_i64(i64_value: Value) -> Result<i64> {
     i64_value.try_into().map_err(EventParseError::InvalidI64)
 }
 
+// Floating window anchors are sent as floats, but older versions of neovim sent integers.
+fn parse_f64(f64_value: Value) -> Result<f64> {
+    match f64_value {
+        Value::F64(value) => Ok(value),
+        Value::F32(value) => Ok(value as f64),
+        Value::Integer(ref value) => value.as_f64().ok_or(EventParseError::InvalidF64(f64_value.clone())),
+        _ => Err(EventParseError::InvalidF64(f64_value))
+    }
+}
+
+// Window handles are sent as a msgpack extension type wrapping the msgpack encoded handle number.
+fn parse_window_handle(window_value: Value) -> Result<u64> {
+    let handle = match &window_value {
+        Value::Ext(_, data) => rmpv::decode::read_value(&mut data.as_slice()).ok().and_then(|handle| handle.as_u64()),
+        Value::Integer(handle) => handle.as_u64(),
+        _ => None
+    };
+
+    handle.ok_or(EventParseError::InvalidWindowHandle(window_value))
+}
+
 fn parse_bool(bool_value: Value) -> Result<bool> {
     bool_value.try_into().map_err(EventParseError::InvalidBool)
 }
@@ -220,6 +291,9 @@ fn parse_mode_info_set(mode_info_set_arguments: Vec<Value>) -> Result<RedrawEven
 
         for (name, value) in info_map {
             match parse_string(name)?.as_str() {
+                "name" => {
+                    mode_info.name = Some(parse_string(value)?);
+                },
                 "cursor_shape" => {
                     mode_info.shape = CursorShape::from_type_name(&parse_string(value)?);
                 },
@@ -273,9 +347,10 @@ fn parse_option_set(option_set_arguments: Vec<Value>) -> Result<RedrawEvent> {
 }
 
 fn parse_mode_change(mode_change_arguments: Vec<Value>) -> Result<RedrawEvent> {
-    let [_mode, mode_index] = extract_values(mode_change_arguments, [Value::Nil, Value::Nil])?;
+    let [mode, mode_index] = extract_values(mode_change_arguments, [Value::Nil, Value::Nil])?;
 
     Ok(RedrawEvent::ModeChange {
+        mode: parse_string(mode)?,
         mode_index: parse_u64(mode_index)?
     })
 }
@@ -310,17 +385,17 @@ fn parse_style(style_map: Value) -> Result<Style> {
 
     for attribute in attributes {
         if let (Value::String(name), value) = attribute {
-            match (name.as_str().unwrap(), value) {
-                ("foreground", Value::Integer(packed_color)) => style.colors.foreground = Some(unpack_color(packed_color.as_u64().unwrap())),
-                ("background", Value::Integer(packed_color)) => style.colors.background = Some(unpack_color(packed_color.as_u64().unwrap())),
-                ("special", Value::Integer(packed_color)) => style.colors.special = Some(unpack_color(packed_color.as_u64().unwrap())),
+            match (name.as_str().unwrap_or_default(), value) {
+                ("foreground", Value::Integer(packed_color)) => style.colors.foreground = packed_color.as_u64().map(unpack_color),
+                ("background", Value::Integer(packed_color)) => style.colors.background = packed_color.as_u64().map(unpack_color),
+                ("special", Value::Integer(packed_color)) => style.colors.special = packed_color.as_u64().map(unpack_color),
                 ("reverse", Value::Boolean(reverse)) => style.reverse = reverse,
                 ("italic", Value::Boolean(italic)) => style.italic = italic,
                 ("bold", Value::Boolean(bold)) => style.bold = bold,
                 ("strikethrough", Value::Boolean(strikethrough)) => style.strikethrough = strikethrough,
                 ("underline", Value::Boolean(underline)) => style.underline = underline,
                 ("undercurl", Value::Boolean(undercurl)) => style.undercurl = undercurl,
-                ("blend", Value::Integer(blend)) => style.blend = blend.as_u64().unwrap() as u8,
+                ("blend", Value::Integer(blend)) => style.blend = blend.as_u64().unwrap_or(0) as u8,
                 _ => println!("Ignored style attribute: {}", name)
             }
         } else {
@@ -339,6 +414,15 @@ fn parse_hl_attr_define(hl_attr_define_arguments: Vec<Value>) -> Result<RedrawEv
     Ok(RedrawEvent::HighlightAttributesDefine { id: parse_u64(id)?, style })
 }
 
+fn parse_hl_group_set(hl_group_set_arguments: Vec<Value>) -> Result<RedrawEvent> {
+    let [name, id] = extract_values(hl_group_set_arguments, [Value::Nil, Value::Nil])?;
+
+    Ok(RedrawEvent::HighlightGroupSet {
+        name: parse_string(name)?,
+        id: parse_u64(id)?
+    })
+}
+
 fn parse_grid_line_cell(grid_line_cell: Value) -> Result<GridLineCell> {
     fn take_value(val: &mut Value) -> Value {
         std::mem::replace(val, Value::Nil)
@@ -408,7 +492,7 @@ fn parse_win_pos(win_pos_arguments: Vec<Value>) -> Result<RedrawEvent> {
 
     Ok(RedrawEvent::WindowPosition {
         grid: parse_u64(grid)?,
-        window: parse_u64(window)?,
+        window: parse_window_handle(window)?,
         start_row: parse_u64(start_row)?,
         start_column: parse_u64(start_column)?,
         width: parse_u64(width)?,
@@ -433,11 +517,11 @@ fn parse_win_float_pos(win_float_pos_arguments: Vec<Value>) -> Result<RedrawEven
 
     Ok(RedrawEvent::WindowFloatPosition {
         grid: parse_u64(grid)?,
-        window: parse_u64(window)?,
+        window: parse_window_handle(window)?,
         anchor: parse_window_anchor(anchor)?,
         anchor_grid: parse_u64(anchor_grid)?,
-        anchor_row: parse_u64(anchor_row)?,
-        anchor_column: parse_u64(anchor_column)?,
+        anchor_row: parse_f64(anchor_row)?,
+        anchor_column: parse_f64(anchor_column)?,
         focusable: parse_bool(focusable)?
     })
 }
@@ -447,7 +531,7 @@ fn parse_win_external_pos(win_external_pos_arguments: Vec<Value>) -> Result<Redr
 
     Ok(RedrawEvent::WindowExternalPosition {
         grid: parse_u64(grid)?,
-        window: parse_u64(window)?
+        window: parse_window_handle(window)?
     })
 }
 
@@ -467,6 +551,14 @@ fn parse_win_close(win_close_arguments: Vec<Value>) -> Result<RedrawEvent> {
     })
 }
 
+fn parse_grid_destroy(grid_destroy_arguments: Vec<Value>) -> Result<RedrawEvent> {
+    let [grid] = extract_values(grid_destroy_arguments, [Value::Nil])?;
+
+    Ok(RedrawEvent::GridDestroy {
+        grid: parse_u64(grid)?
+    })
+}
+
 fn parse_msg_set_pos(msg_set_pos_arguments: Vec<Value>) -> Result<RedrawEvent> {
     let values = [Value::Nil, Value::Nil, Value::Nil, Value::Nil];
     let [grid, row, scrolled, separator_character] = extract_values(msg_set_pos_arguments, values)?;
@@ -593,7 +685,137 @@ fn parse_msg_history_show(msg_history_show_arguments: Vec<Value>) -> Result<Redr
     })
 }
 
-pub fn parse_redraw_event(event_value: Value) -> Result<Vec<RedrawEvent>> {
+fn parse_popupmenu_item(item: Value) -> Result<PopupMenuItem> {
+    let values = [Value::Nil, Value::Nil, Value::Nil, Value::Nil];
+    let [word, kind, menu, info] = extract_values(parse_array(item)?, values)?;
+
+    Ok(PopupMenuItem {
+        word: parse_string(word)?,
+        kind: parse_string(kind)?,
+        menu: parse_string(menu)?,
+        info: parse_string(info)?
+    })
+}
+
+// Neovim sends -1 when no item is selected.
+fn parse_popupmenu_selection(selected: Value) -> Result<Option<u64>> {
+    let selected = parse_i64(selected)?;
+    Ok(if selected < 0 { None } else { Some(selected as u64) })
+}
+
+fn parse_popupmenu_show(popupmenu_show_arguments: Vec<Value>) -> Result<RedrawEvent> {
+    let values = [Value::Nil, Value::Nil, Value::Nil, Value::Nil, Value::Nil];
+    let [items, selected, row, column, grid] = extract_values(popupmenu_show_arguments, values)?;
+
+    Ok(RedrawEvent::PopupMenuShow {
+        items: parse_array(items)?
+            .into_iter()
+            .map(parse_popupmenu_item)
+            .collect::<Result<_>>()?,
+        selected: parse_popupmenu_selection(selected)?,
+        row: parse_u64(row)?,
+        column: parse_u64(column)?,
+        grid: parse_i64(grid)?
+    })
+}
+
+fn parse_popupmenu_select(popupmenu_select_arguments: Vec<Value>) -> Result<RedrawEvent> {
+    let [selected] = extract_values(popupmenu_select_arguments, [Value::Nil])?;
+
+    Ok(RedrawEvent::PopupMenuSelect {
+        selected: parse_popupmenu_selection(selected)?
+    })
+}
+
+fn parse_tab_info(tab_info: Value) -> Result<TabInfo> {
+    let mut tab = None;
+    let mut name = None;
+
+    for (key, value) in parse_map(tab_info)? {
+        match parse_string(key)?.as_str() {
+            "tab" => tab = Some(value),
+            "name" => name = Some(parse_string(value)?),
+            _ => {}
+        }
+    }
+
+    Ok(TabInfo {
+        tab: tab.ok_or(EventParseError::InvalidEventFormat)?,
+        name: name.unwrap_or_default()
+    })
+}
+
+fn parse_tabline_update(tabline_update_arguments: Vec<Value>) -> Result<RedrawEvent> {
+    let [current_tab, tabs] = extract_values(tabline_update_arguments, [Value::Nil, Value::Nil])?;
+
+    Ok(RedrawEvent::TablineUpdate {
+        current_tab,
+        tabs: parse_array(tabs)?
+            .into_iter()
+            .map(parse_tab_info)
+            .collect::<Result<_>>()?
+    })
+}
+
+fn parse_event(event_name: &str, event: Value) -> Result<Option<RedrawEvent>> {
+    let event_parameters = parse_array(event)?;
+    let possible_parsed_event = match event_name {
+        "set_title" => Some(parse_set_title(event_parameters)?),
+        "set_icon" => None, // Ignore set icon for now
+        "mode_info_set" => Some(parse_mode_info_set(event_parameters)?),
+        "option_set" => Some(parse_option_set(event_parameters)?),
+        "mode_change" => Some(parse_mode_change(event_parameters)?),
+        "busy_start" => Some(RedrawEvent::BusyStart),
+        "busy_stop" => Some(RedrawEvent::BusyStop),
+        "bell" => Some(RedrawEvent::Bell),
+        "visual_bell" => Some(RedrawEvent::VisualBell),
+        "flush" => Some(RedrawEvent::Flush),
+        "grid_resize" => Some(parse_grid_resize(event_parameters)?),
+        "default_colors_set" => Some(parse_default_colors(event_parameters)?),
+        "hl_attr_define" => Some(parse_hl_attr_define(event_parameters)?),
+        "hl_group_set" => Some(parse_hl_group_set(event_parameters)?),
+        "grid_line" => Some(parse_grid_line(event_parameters)?),
+        "grid_clear" => Some(parse_clear(event_parameters)?),
+        "grid_cursor_goto" => Some(parse_cursor_goto(event_parameters)?),
+        "grid_scroll" => Some(parse_grid_scroll(event_parameters)?),
+        "grid_destroy" => Some(parse_grid_destroy(event_parameters)?),
+        "win_pos" => Some(parse_win_pos(event_parameters)?),
+        "win_float_pos" => Some(parse_win_float_pos(event_parameters)?),
+        "win_external_pos" => Some(parse_win_external_pos(event_parameters)?),
+        "win_hide" => Some(parse_win_hide(event_parameters)?),
+        "win_close" => Some(parse_win_close(event_parameters)?),
+        "msg_set_pos" => Some(parse_msg_set_pos(event_parameters)?),
+        "cmdline_show" => Some(parse_cmdline_show(event_parameters)?),
+        "cmdline_pos" => Some(parse_cmdline_pos(event_parameters)?),
+        "cmdline_special_char" => Some(parse_cmdline_special_char(event_parameters)?),
+        "cmdline_hide" => Some(RedrawEvent::CommandLineHide),
+        "cmdline_block_show" => Some(parse_cmdline_block_show(event_parameters)?),
+        "cmdline_block_append" => Some(parse_cmdline_block_append(event_parameters)?),
+        "cmdline_block_hide" => Some(RedrawEvent::CommandLineBlockHide),
+        "msg_show" => Some(parse_msg_show(event_parameters)?),
+        "msg_clear" => Some(RedrawEvent::MessageClear),
+        "msg_showmode" => Some(parse_msg_showmode(event_parameters)?),
+        "msg_showcmd" => Some(parse_msg_showcmd(event_parameters)?),
+        "msg_ruler" => Some(parse_msg_ruler(event_parameters)?),
+        "msg_history_show" => Some(parse_msg_history_show(event_parameters)?),
+        "popupmenu_show" => Some(parse_popupmenu_show(event_parameters)?),
+        "popupmenu_select" => Some(parse_popupmenu_select(event_parameters)?),
+        "popupmenu_hide" => Some(RedrawEvent::PopupMenuHide),
+        "tabline_update" => Some(parse_tabline_update(event_parameters)?),
+        _ => None
+    };
+
+    Ok(possible_parsed_event)
+}
+
+fn skip_event(error: &EventParseError) {
+    let skipped_count = SKIPPED_EVENT_COUNT.fetch_add(1, Ordering::Relaxed) + 1;
+    warn!("Skipped redraw event ({} skipped so far): {}", skipped_count, error);
+}
+
+// Parses every argument list in a redraw event batch, returning the events which parsed along
+// with the errors for the ones which didn't.
+fn parse_event_batch(event_value: Value) -> Result<(Vec<RedrawEvent>, Vec<EventParseError>)> {
     let mut event_contents = parse_array(event_value)?.into_iter();
     let event_name = event_contents.next()
         .ok_or(EventParseError::InvalidEventFormat)
@@ -601,69 +823,191 @@ pub fn parse_redraw_event(event_value: Value) -> Result<Vec<RedrawEvent>> {
 
     let events = event_contents;
     let mut parsed_events = Vec::with_capacity(events.len());
+    let mut errors = Vec::new();
+
+    for (argument_index, event) in events.enumerate() {
+        match parse_event(&event_name, event) {
+            Ok(Some(parsed_event)) => parsed_events.push(parsed_event),
+            Ok(None) => {},
+            Err(error) => errors.push(EventParseError::InvalidEventArguments {
+                event_name: event_name.clone(),
+                argument_index,
+                error: Box::new(error)
+            })
+        }
+    }
 
-    for event in events {
-        let event_parameters = parse_array(event)?;
-        let possible_parsed_event = match event_name.as_str() {
-            "set_title" => Some(parse_set_title(event_parameters)?),
-            "set_icon" => None, // Ignore set icon for now
-            "mode_info_set" => Some(parse_mode_info_set(event_parameters)?),
-            "option_set" => Some(parse_option_set(event_parameters)?),
-            "mode_change" => Some(parse_mode_change(event_parameters)?),
-            "busy_start" => Some(RedrawEvent::BusyStart),
-            "busy_stop" => Some(RedrawEvent::BusyStop),
-            "flush" => Some(RedrawEvent::Flush),
-            "grid_resize" => Some(parse_grid_resize(event_parameters)?),
-            "default_colors_set" => Some(parse_default_colors(event_parameters)?),
-            "hl_attr_define" => Some(parse_hl_attr_define(event_parameters)?),
-            "grid_line" => Some(parse_grid_line(event_parameters)?),
-            "grid_clear" => Some(parse_clear(event_parameters)?),
-            "grid_cursor_goto" => Some(parse_cursor_goto(event_parameters)?),
-            "grid_scroll" => Some(parse_grid_scroll(event_parameters)?),
-            "win_pos" => Some(parse_win_pos(event_parameters)?),
-            "win_float_pos" => Some(parse_win_float_pos(event_parameters)?),
-            "win_external_pos" => Some(parse_win_external_pos(event_parameters)?),
-            "win_hide" => Some(parse_win_hide(event_parameters)?),
-            "win_close" => Some(parse_win_close(event_parameters)?),
-            "msg_set_pos" => Some(parse_msg_set_pos(event_parameters)?),
-            "cmdline_show" => Some(parse_cmdline_show(event_parameters)?),
-            "cmdline_pos" => Some(parse_cmdline_pos(event_parameters)?),
-            "cmdline_special_char" => Some(parse_cmdline_special_char(event_parameters)?),
-            "cmdline_hide" => Some(RedrawEvent::CommandLineHide),
-            "cmdline_block_show" => Some(parse_cmdline_block_show(event_parameters)?),
-            "cmdline_block_append" => Some(parse_cmdline_block_append(event_parameters)?),
-            "cmdline_block_hide" => Some(RedrawEvent::CommandLineBlockHide),
-            "msg_show" => Some(parse_msg_show(event_parameters)?),
-            "msg_clear" => Some(RedrawEvent::MessageClear),
-            "msg_showmode" => Some(parse_msg_showmode(event_parameters)?),
-            "msg_showcmd" => Some(parse_msg_showcmd(event_parameters)?),
-            "msg_ruler" => Some(parse_msg_ruler(event_parameters)?),
-            "msg_history_show" => Some(parse_msg_history_show(event_parameters)?),
-            _ => None
-        };
+    Ok((parsed_events, errors))
+}
 
-        if let Some(parsed_event) = possible_parsed_event {
-            parsed_events.push(parsed_event);
-        }
+// Parses every event in a redraw event batch. Events which fail to parse are logged and skipped so
+// that a single unexpected argument doesn't take down the rest of the batch.
+pub fn parse_redraw_event(event_value: Value) -> Result<Vec<RedrawEvent>> {
+    let (parsed_events, errors) = parse_event_batch(event_value)?;
+    for error in errors.iter() {
+        skip_event(error);
     }
 
     Ok(parsed_events)
 }
 
 pub(in super) fn handle_redraw_event_group(arguments: Vec<Value>) {
+    let mut parsed_events = Vec::new();
     for events in arguments {
-        let parsed_events = parse_redraw_event(events)
-            .unwrap_or_explained_panic("Could not parse event from neovim");
-
-        for parsed_event in parsed_events {
-            let mut editor = EDITOR.lock();
-            editor.handle_redraw_event(parsed_event);
+        match parse_redraw_event(events) {
+            Ok(events) => parsed_events.extend(events),
+            Err(error) => skip_event(&error)
         }
     }
+
+    // Apply the whole batch under a single lock so that nothing reading the editor can observe it
+    // half applied.
+    let mut editor = EDITOR.lock();
+    for parsed_event in parsed_events {
+        editor.handle_redraw_event(parsed_event);
+    }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EVENT_NAMES: &[&str] = &[
+        "set_title", "set_icon", "mode_info_set", "option_set", "mode_change", "busy_start",
+        "busy_stop", "bell", "visual_bell", "flush", "grid_resize", "default_colors_set",
+        "hl_attr_define", "hl_group_set", "grid_line", "grid_clear", "grid_cursor_goto",
+        "grid_scroll", "grid_destroy", "win_pos", "win_float_pos", "win_external_pos", "win_hide",
+        "win_close", "msg_set_pos", "cmdline_show", "cmdline_pos", "cmdline_special_char",
+        "cmdline_hide", "cmdline_block_show", "cmdline_block_append", "cmdline_block_hide",
+        "msg_show", "msg_clear", "msg_showmode", "msg_showcmd", "msg_ruler", "msg_history_show",
+        "popupmenu_show", "popupmenu_select", "popupmenu_hide", "tabline_update", "not_an_event"
+    ];
+
+    // Xorshift, so that failures can be reproduced from the seed without pulling in a crate.
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, bound: u64) -> u64 {
+            self.next() % bound
+        }
+    }
+
+    fn random_string(rng: &mut Rng) -> String {
+        match rng.below(4) {
+            0 => EVENT_NAMES[rng.below(EVENT_NAMES.len() as u64) as usize].to_string(),
+            1 => ["NW", "NE", "SW", "SE", "guifont", "name", "foreground", "blend", "emsg", "é", ""]
+                [rng.below(11) as usize].to_string(),
+            _ => (0..rng.below(6)).map(|_| (b'a' + rng.below(26) as u8) as char).collect()
+        }
+    }
 
+    fn random_value(rng: &mut Rng, depth: u32) -> Value {
+        let kinds = if depth == 0 { 7 } else { 9 };
+        match rng.below(kinds) {
+            0 => Value::Nil,
+            1 => Value::from(rng.below(2) == 0),
+            2 => Value::from(rng.below(300)),
+            3 => Value::from(-(rng.below(300) as i64)),
+            4 => Value::from(rng.next() as f64 / std::u64::MAX as f64 * 100.0),
+            5 => Value::from(random_string(rng)),
+            6 => Value::Ext(1, vec![rng.below(256) as u8]),
+            7 => Value::Array((0..rng.below(8)).map(|_| random_value(rng, depth - 1)).collect()),
+            _ => Value::Map((0..rng.below(4))
+                .map(|_| (Value::from(random_string(rng)), random_value(rng, depth - 1)))
+                .collect())
+        }
+    }
 
+    // A batch shaped like the ones neovim sends: an event name followed by argument lists.
+    fn random_batch(rng: &mut Rng) -> Value {
+        let name = Value::from(EVENT_NAMES[rng.below(EVENT_NAMES.len() as u64) as usize]);
+        let mut batch = vec![name];
+        for _ in 0..rng.below(4) {
+            batch.push(random_value(rng, 3));
+        }
+        Value::Array(batch)
+    }
 
+    #[test]
+    fn random_values_never_panic() {
+        for seed in 1..2000 {
+            let mut rng = Rng(seed);
+            for _ in 0..5 {
+                parse_event_batch(random_batch(&mut rng)).ok();
+                parse_event_batch(random_value(&mut rng, 4)).ok();
+            }
+        }
+    }
 
+    #[test]
+    fn trailing_arguments_are_ignored() {
+        let batch = Value::Array(vec![
+            Value::from("grid_resize"),
+            Value::Array(vec![Value::from(1), Value::from(80), Value::from(24), Value::from("extra"), Value::from(5)])
+        ]);
+
+        let (events, errors) = parse_event_batch(batch).unwrap();
+        assert!(errors.is_empty());
+        match events.as_slice() {
+            [RedrawEvent::Resize { grid: 1, width: 80, height: 24 }] => {},
+            events => panic!("Unexpected events {:?}", events)
+        }
+    }
 
+    #[test]
+    fn missing_arguments_are_an_error() {
+        let batch = Value::Array(vec![
+            Value::from("grid_resize"),
+            Value::Array(vec![Value::from(1), Value::from(80)])
+        ]);
+
+        let (events, errors) = parse_event_batch(batch).unwrap();
+        assert!(events.is_empty());
+        assert_eq!(errors.len(), 1);
+    }
+
+    #[test]
+    fn invalid_arguments_carry_the_event_name_and_index() {
+        let batch = Value::Array(vec![
+            Value::from("grid_clear"),
+            Value::Array(vec![Value::from(1)]),
+            Value::Array(vec![Value::from("not a grid")]),
+            Value::Array(vec![Value::from(3)])
+        ]);
+
+        let (events, errors) = parse_event_batch(batch).unwrap();
+        match events.as_slice() {
+            [RedrawEvent::Clear { grid: 1 }, RedrawEvent::Clear { grid: 3 }] => {},
+            events => panic!("Unexpected events {:?}", events)
+        }
+        match errors.as_slice() {
+            [EventParseError::InvalidEventArguments { event_name, argument_index: 1, error }] => {
+                assert_eq!(event_name, "grid_clear");
+                match error.as_ref() {
+                    EventParseError::InvalidU64(_) => {},
+                    error => panic!("Unexpected inner error {:?}", error)
+                }
+            },
+            errors => panic!("Unexpected errors {:?}", errors)
+        }
+    }
+
+    #[test]
+    fn batches_which_arent_arrays_are_rejected() {
+        match parse_redraw_event(Value::from(5)) {
+            Err(EventParseError::InvalidArray(_)) => {},
+            result => panic!("Unexpected result {:?}", result)
+        }
+        match parse_redraw_event(Value::Array(Vec::new())) {
+            Err(EventParseError::InvalidEventFormat) => {},
+            result => panic!("Unexpected result {:?}", result)
+        }
+    }
+}
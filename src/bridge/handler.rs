@@ -1,21 +1,35 @@
+use std::marker::PhantomData;
+
 use rmpv::Value;
-use nvim_rs::{Neovim, Handler, compat::tokio::Compat};
+use nvim_rs::{Neovim, Handler};
 use async_trait::async_trait;
-use tokio::process::ChildStdin;
 use tokio::task;
 use log::trace;
 
 use crate::settings::SETTINGS;
+use super::NeovimWriter;
 use super::events::handle_redraw_event_group;
+use super::clipboard::{get_clipboard, set_clipboard, ClipboardContents};
+
+pub struct NeovimHandler<W: NeovimWriter>(PhantomData<fn() -> W>);
+
+impl<W: NeovimWriter> NeovimHandler<W> {
+    pub fn new() -> NeovimHandler<W> {
+        NeovimHandler(PhantomData)
+    }
+}
 
-#[derive(Clone)]
-pub struct NeovimHandler();
+impl<W: NeovimWriter> Clone for NeovimHandler<W> {
+    fn clone(&self) -> NeovimHandler<W> {
+        NeovimHandler::new()
+    }
+}
 
 #[async_trait]
-impl Handler for NeovimHandler {
-    type Writer = Compat<ChildStdin>;
+impl<W: NeovimWriter> Handler for NeovimHandler<W> {
+    type Writer = W;
 
-    async fn handle_notify(&self, event_name: String, arguments: Vec<Value>, _neovim: Neovim<Compat<ChildStdin>>) {
+    async fn handle_notify(&self, event_name: String, arguments: Vec<Value>, _neovim: Neovim<W>) {
         trace!("Neovim notification: {:?}", &event_name);
         task::spawn_blocking(move || {
             match event_name.as_ref() {
@@ -29,4 +43,22 @@ impl Handler for NeovimHandler {
             }
         }).await.ok();
     }
+
+    async fn handle_request(&self, event_name: String, arguments: Vec<Value>, _neovim: Neovim<W>) -> Result<Value, Value> {
+        trace!("Neovim request: {:?}", &event_name);
+
+        match event_name.as_ref() {
+            "neovide.get_clipboard" => get_clipboard().await
+                .map(ClipboardContents::into_value)
+                .ok_or_else(|| Value::from("Could not read the clipboard")),
+            "neovide.set_clipboard" => {
+                let contents = arguments.get(0)
+                    .and_then(|lines| ClipboardContents::parse(lines, arguments.get(1)))
+                    .ok_or_else(|| Value::from("Clipboard contents must be a list of lines"))?;
+                set_clipboard(contents);
+                Ok(Value::Nil)
+            },
+            _ => Err(Value::from(format!("Unknown request {}", event_name)))
+        }
+    }
 }
@@ -2,7 +2,10 @@ use log::trace;
 // use skulpin::winit::event::{ElementState, KeyboardInput, ModifiersState, VirtualKeyCode};
 use skulpin::sdl2::keyboard::{Keycode, Mod};
 
-pub fn parse_keycode(keycode: Keycode) -> Option<(&'static str, bool)> {
+// Name of the key in neovim's key notation along with whether it is special, meaning it doesn't
+// type a character of its own. Keys which aren't special are typed through the text the OS
+// produces for them unless a modifier turns them into a shortcut.
+fn parse_keycode(keycode: Keycode) -> Option<(&'static str, bool)> {
     macro_rules! unsupported_key {
         ($name: ident) => {{
             if cfg!(debug_assertions) {
@@ -46,9 +49,9 @@ pub fn parse_keycode(keycode: Keycode) -> Option<(&'static str, bool)> {
         Keycode::Num9 => Some(("9", false)),
         Keycode::Colon => Some((":", false)),
         Keycode::Semicolon => Some((";", false)),
-        Keycode::Less => Some(("lt", true)),
+        Keycode::Less => Some(("<", false)),
         Keycode::Equals => Some(("=", false)),
-        Keycode::Greater => Some(("gt", true)),
+        Keycode::Greater => Some((">", false)),
         Keycode::Question => Some(("?", false)),
         Keycode::At => Some(("@", false)),
         Keycode::LeftBracket => Some(("[", false)),
@@ -110,10 +113,10 @@ pub fn parse_keycode(keycode: Keycode) -> Option<(&'static str, bool)> {
         Keycode::Down => Some(("Down", true)),
         Keycode::Up => Some(("Up", true)),
         Keycode::NumLockClear => unsupported_key!(NumLockClear),
-        Keycode::KpDivide => Some(("/", true)),
-        Keycode::KpMultiply => Some(("*", true)),
-        Keycode::KpMinus => Some(("-", true)),
-        Keycode::KpPlus => Some(("+", true)),
+        Keycode::KpDivide => Some(("/", false)),
+        Keycode::KpMultiply => Some(("*", false)),
+        Keycode::KpMinus => Some(("-", false)),
+        Keycode::KpPlus => Some(("+", false)),
         Keycode::KpEnter => Some(("Enter", true)),
         Keycode::Kp0 => Some(("0", false)),
         Keycode::Kp1 => Some(("1", false)),
@@ -179,7 +182,7 @@ pub fn parse_keycode(keycode: Keycode) -> Option<(&'static str, bool)> {
         Keycode::KpRightParen => Some((")", false)),
         Keycode::KpLeftBrace => Some(("[", false)),
         Keycode::KpRightBrace => Some(("]", false)),
-        Keycode::KpTab => Some(("TAB", true)),
+        Keycode::KpTab => Some(("Tab", true)),
         Keycode::KpBackspace => Some(("BS", true)),
         Keycode::KpA => Some(("A", false)),
         Keycode::KpB => Some(("B", false)),
@@ -190,8 +193,8 @@ pub fn parse_keycode(keycode: Keycode) -> Option<(&'static str, bool)> {
         Keycode::KpXor => unsupported_key!(KpXor),
         Keycode::KpPower => Some(("^", false)),
         Keycode::KpPercent => Some(("%", false)),
-        Keycode::KpLess => Some(("lt", true)),
-        Keycode::KpGreater => Some(("gt", true)),
+        Keycode::KpLess => Some(("<", false)),
+        Keycode::KpGreater => Some((">", false)),
         Keycode::KpAmpersand => Some(("&", false)),
         Keycode::KpDblAmpersand => unsupported_key!(KpDblAmpersand),
         Keycode::KpVerticalBar => Some(("|", false)),
@@ -252,30 +255,385 @@ pub fn parse_keycode(keycode: Keycode) -> Option<(&'static str, bool)> {
     }
 }
 
-pub fn append_modifiers(modifiers: Mod, keycode_text: &str, special: bool) -> String {
-    let mut result = keycode_text.to_string();
-    let mut special = special;
+// Characters which can't appear inside angle brackets as they are, or at all in the case of <.
+fn escape_key_name(name: &str) -> &str {
+    match name {
+        " " => "Space",
+        "<" => "lt",
+        "\\" => "Bslash",
+        "|" => "Bar",
+        _ => name
+    }
+}
+
+// Text typed without any shortcut modifiers goes to nvim_input as is, where only < means
+// anything special.
+fn escape_text(text: &str) -> String {
+    text.replace('<', "<lt>")
+}
+
+// Characters the US layout types with Shift held.
+fn shifted_us_character(name: &str) -> Option<&'static str> {
+    match name {
+        "1" => Some("!"),
+        "2" => Some("@"),
+        "3" => Some("#"),
+        "4" => Some("$"),
+        "5" => Some("%"),
+        "6" => Some("^"),
+        "7" => Some("&"),
+        "8" => Some("*"),
+        "9" => Some("("),
+        "0" => Some(")"),
+        "-" => Some("_"),
+        "=" => Some("+"),
+        "[" => Some("{"),
+        "]" => Some("}"),
+        "\\" => Some("|"),
+        ";" => Some(":"),
+        "'" => Some("\""),
+        "," => Some("<"),
+        "." => Some(">"),
+        "/" => Some("?"),
+        "`" => Some("~"),
+        _ => None
+    }
+}
+
+// The text a key types when text input is off and the OS doesn't say. Keycodes follow the layout
+// for unshifted keys, but what Shift gives other keys than letters isn't known, so the US layout
+// is assumed. With Ctrl, Alt or Cmd held nothing is typed, leaving the key to be a shortcut.
+pub fn keycode_text(keycode: Keycode, modifiers: Mod) -> Option<String> {
+    let shortcut_modifiers = Mod::LCTRLMOD | Mod::RCTRLMOD | Mod::LALTMOD | Mod::RALTMOD |
+        Mod::LGUIMOD | Mod::RGUIMOD | Mod::MODEMOD;
+    if modifiers.intersects(shortcut_modifiers) {
+        return None;
+    }
+
+    let (name, special) = parse_keycode(keycode)?;
+    if special {
+        return None;
+    }
+
+    let shift = modifiers.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD);
+    let is_letter = name.len() == 1 && name.chars().all(|character| character.is_ascii_alphabetic());
+    if is_letter {
+        let upper_case = shift != modifiers.contains(Mod::CAPSMOD);
+        Some(if upper_case { name.to_ascii_uppercase() } else { name.to_string() })
+    } else if shift {
+        Some(shifted_us_character(name).unwrap_or(name).to_string())
+    } else {
+        Some(name.to_string())
+    }
+}
+
+// A key press put together from SDL, which reports the key and the text it typed as separate
+// KeyDown and TextInput events.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeyPress {
+    pub keycode: Option<Keycode>,
+    pub modifiers: Mod,
+    pub text: Option<String>
+}
+
+// Pairs KeyDown events with the TextInput event following them. With text input on, a key is held
+// back until its text arrives or until another event shows that none is coming. Text typed
+// without a key, like text committed by an input method, comes through on its own. With text
+// input off no text arrives, so keys are passed on right away with their keycode_text.
+pub struct KeyPairing {
+    text_input: bool,
+    pending: Option<(Keycode, Mod)>
+}
+
+impl KeyPairing {
+    pub fn new(text_input: bool) -> KeyPairing {
+        KeyPairing { text_input, pending: None }
+    }
+
+    pub fn key_down(&mut self, keycode: Keycode, modifiers: Mod) -> Option<KeyPress> {
+        if !self.text_input {
+            let text = keycode_text(keycode, modifiers);
+            return Some(KeyPress { keycode: Some(keycode), modifiers, text });
+        }
+
+        self.pending.replace((keycode, modifiers))
+            .map(|(keycode, modifiers)| KeyPress { keycode: Some(keycode), modifiers, text: None })
+    }
+
+    pub fn text_input(&mut self, text: String) -> KeyPress {
+        let (keycode, modifiers) = match self.pending.take() {
+            Some((keycode, modifiers)) => (Some(keycode), modifiers),
+            None => (None, Mod::NOMOD)
+        };
+        KeyPress { keycode, modifiers, text: Some(text) }
+    }
+
+    // Passes on the key held back without any text. Called for any other event, and once the
+    // events run out since the text would have arrived with them.
+    pub fn flush(&mut self) -> Option<KeyPress> {
+        self.pending.take()
+            .map(|(keycode, modifiers)| KeyPress { keycode: Some(keycode), modifiers, text: None })
+    }
+}
+
+// Turns a key press into neovim's key notation. The keycode and modifiers come from SDL's
+// KeyDown event and the text from the TextInput event which followed it, if any. Returns None
+// when nothing should be sent, such as for modifier keys on their own or for character keys
+// whose text hasn't arrived yet.
+//
+// Right Alt is taken to be AltGr when it changed the text the key typed, and on Windows AltGr is
+// reported as left Ctrl along with right Alt. Either way the layout already picked the character,
+// so the text is sent as typed. Left Alt is Meta unless alt_is_meta is off, in which case it too
+// only picks characters like on macOS, and is Meta only for keys which type nothing.
+pub fn encode_key(keycode: Option<Keycode>, modifiers: Mod, text: Option<&str>, alt_is_meta: bool) -> Option<String> {
+    let key = keycode.and_then(parse_keycode);
+
+    let right_alt = modifiers.contains(Mod::RALTMOD);
+    let left_alt = modifiers.contains(Mod::LALTMOD);
+    let typed_other_character = match (key, text) {
+        (Some((name, false)), Some(text)) => !text.eq_ignore_ascii_case(name),
+        (None, Some(_)) => true,
+        _ => false
+    };
+    let altgr = modifiers.contains(Mod::MODEMOD) || (right_alt && typed_other_character);
+
+    let shift = modifiers.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD);
+    let control = modifiers.contains(Mod::RCTRLMOD) ||
+        (modifiers.contains(Mod::LCTRLMOD) && !(altgr && right_alt));
+    let meta = (left_alt && (alt_is_meta || text.is_none())) || (right_alt && !altgr);
+    let command = modifiers.intersects(Mod::LGUIMOD | Mod::RGUIMOD);
+
+    if !control && !meta && !command {
+        if let Some(text) = text {
+            return Some(escape_text(text));
+        }
+    }
+
+    let (name, special) = key?;
+    let is_letter = name.len() == 1 && name.chars().all(|character| character.is_ascii_alphabetic());
+    // Shifted punctuation depends on the layout, so the character the OS typed is used rather
+    // than the key's own.
+    let typed_name = text.filter(|text| !special && !is_letter && text.chars().count() == 1);
+    let name = typed_name.unwrap_or(name);
+
+    // Keys which type something wait for their text, unless a modifier makes them a shortcut.
+    if !special && !control && !meta && !command {
+        return None;
+    }
 
-    if modifiers.contains(Mod::LSHIFTMOD) || modifiers.contains(Mod::RSHIFTMOD) {
-        special = true;
+    let mut result = escape_key_name(name).to_string();
+    // A typed character already came out shifted. Without one there is no telling what Shift
+    // would have typed on this layout, so it is kept for mappings like <C-S-1> to match.
+    if shift && typed_name.is_none() {
         result = format!("S-{}", result);
     }
-    if modifiers.contains(Mod::LCTRLMOD) || modifiers.contains(Mod::RCTRLMOD) {
-        special = true;
+    if control {
         result = format!("C-{}", result);
     }
-    if modifiers.contains(Mod::LALTMOD) || modifiers.contains(Mod::RALTMOD) {
-        special = true;
+    if meta {
         result = format!("M-{}", result);
     }
-    if modifiers.contains(Mod::LGUIMOD) || modifiers.contains(Mod::RGUIMOD) {
-        special = true;
+    if command {
         result = format!("D-{}", result);
     }
 
-    if special {
-        result = format!("<{}>", result);
+    Some(format!("<{}>", result))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The keycode, modifiers and text of a key press, whether left Alt is Meta, and the notation
+    // expected for it.
+    type Case<'a> = (Option<Keycode>, Mod, Option<&'a str>, bool, Option<&'a str>);
+
+    fn check(cases: &[Case]) {
+        for &(keycode, modifiers, text, alt_is_meta, expected) in cases {
+            assert_eq!(
+                encode_key(keycode, modifiers, text, alt_is_meta).as_ref().map(String::as_str), expected,
+                "{:?} {:?} {:?} alt_is_meta: {}", keycode, modifiers, text, alt_is_meta);
+        }
+    }
+
+    #[test]
+    fn keycodes_type_text_without_text_input() {
+        assert_eq!(keycode_text(Keycode::J, Mod::NOMOD), Some("j".to_string()));
+        assert_eq!(keycode_text(Keycode::J, Mod::LSHIFTMOD), Some("J".to_string()));
+        assert_eq!(keycode_text(Keycode::J, Mod::CAPSMOD), Some("J".to_string()));
+        assert_eq!(keycode_text(Keycode::J, Mod::CAPSMOD | Mod::RSHIFTMOD), Some("j".to_string()));
+        assert_eq!(keycode_text(Keycode::Semicolon, Mod::LSHIFTMOD), Some(":".to_string()));
+        assert_eq!(keycode_text(Keycode::Num4, Mod::LSHIFTMOD), Some("$".to_string()));
+        assert_eq!(keycode_text(Keycode::Slash, Mod::NOMOD), Some("/".to_string()));
+        assert_eq!(keycode_text(Keycode::Space, Mod::NOMOD), Some(" ".to_string()));
+        assert_eq!(keycode_text(Keycode::J, Mod::LCTRLMOD), None);
+        assert_eq!(keycode_text(Keycode::J, Mod::LALTMOD), None);
+        assert_eq!(keycode_text(Keycode::Escape, Mod::NOMOD), None);
+        assert_eq!(keycode_text(Keycode::LShift, Mod::LSHIFTMOD), None);
+    }
+
+    #[test]
+    fn keycode_text_encodes_like_text_input() {
+        let encode = |keycode, modifiers| encode_key(Some(keycode), modifiers, keycode_text(keycode, modifiers).as_ref().map(String::as_str), true);
+        assert_eq!(encode(Keycode::Semicolon, Mod::LSHIFTMOD), Some(":".to_string()));
+        assert_eq!(encode(Keycode::Comma, Mod::LSHIFTMOD), Some("<lt>".to_string()));
+        assert_eq!(encode(Keycode::J, Mod::LCTRLMOD), Some("<C-j>".to_string()));
+        assert_eq!(encode(Keycode::J, Mod::LALTMOD), Some("<M-j>".to_string()));
+    }
+
+    #[test]
+    fn letters_and_special_keys() {
+        check(&[
+            (Some(Keycode::A), Mod::NOMOD, None, true, None),
+            (Some(Keycode::A), Mod::NOMOD, Some("a"), true, Some("a")),
+            (Some(Keycode::A), Mod::LSHIFTMOD, Some("A"), true, Some("A")),
+            (Some(Keycode::A), Mod::LCTRLMOD, None, true, Some("<C-a>")),
+            (Some(Keycode::A), Mod::LCTRLMOD | Mod::LSHIFTMOD, None, true, Some("<C-S-a>")),
+            (Some(Keycode::V), Mod::LGUIMOD, None, true, Some("<D-v>")),
+            (Some(Keycode::Space), Mod::NOMOD, Some(" "), true, Some(" ")),
+            (Some(Keycode::Space), Mod::LCTRLMOD, None, true, Some("<C-Space>")),
+            (Some(Keycode::F1), Mod::RSHIFTMOD, None, true, Some("<S-F1>")),
+            (Some(Keycode::Left), Mod::LCTRLMOD | Mod::LSHIFTMOD | Mod::LALTMOD, None, true, Some("<M-C-S-Left>")),
+            (Some(Keycode::LShift), Mod::LSHIFTMOD, None, true, None),
+            (None, Mod::NOMOD, Some("é"), true, Some("é")),
+        ]);
+    }
+
+    #[test]
+    fn shifted_punctuation_and_keypad() {
+        check(&[
+            (Some(Keycode::Num1), Mod::LSHIFTMOD, Some("!"), true, Some("!")),
+            // What Shift types depends on the layout, so it is sent along rather than guessed.
+            (Some(Keycode::Num1), Mod::LCTRLMOD | Mod::LSHIFTMOD, None, true, Some("<C-S-1>")),
+            (Some(Keycode::Num1), Mod::LCTRLMOD | Mod::LSHIFTMOD, Some("!"), true, Some("<C-!>")),
+            (Some(Keycode::KpTab), Mod::NOMOD, None, true, Some("<Tab>")),
+            (Some(Keycode::KpTab), Mod::LSHIFTMOD, None, true, Some("<S-Tab>")),
+            (Some(Keycode::KpMinus), Mod::NOMOD, None, true, None),
+            (Some(Keycode::KpMinus), Mod::NOMOD, Some("-"), true, Some("-")),
+            (Some(Keycode::KpEnter), Mod::NOMOD, None, true, Some("<Enter>")),
+        ]);
+    }
+
+    #[test]
+    fn characters_which_need_escaping() {
+        check(&[
+            (Some(Keycode::Backslash), Mod::NOMOD, Some("\\"), true, Some("\\")),
+            (Some(Keycode::Backslash), Mod::LCTRLMOD, None, true, Some("<C-Bslash>")),
+            (Some(Keycode::Backslash), Mod::LSHIFTMOD | Mod::LALTMOD, Some("|"), true, Some("<M-Bar>")),
+            (Some(Keycode::Less), Mod::NOMOD, Some("<"), true, Some("<lt>")),
+            (Some(Keycode::Comma), Mod::LSHIFTMOD, Some("<"), true, Some("<lt>")),
+            (Some(Keycode::Comma), Mod::LSHIFTMOD | Mod::LALTMOD, Some("<"), true, Some("<M-lt>")),
+            (None, Mod::NOMOD, Some("a<b"), true, Some("a<lt>b")),
+        ]);
+    }
+
+    #[test]
+    fn altgr_types_characters() {
+        check(&[
+            // Most platforms report AltGr as right Alt.
+            (Some(Keycode::Q), Mod::RALTMOD, Some("@"), true, Some("@")),
+            // Windows adds left Ctrl to it.
+            (Some(Keycode::Q), Mod::LCTRLMOD | Mod::RALTMOD, Some("@"), true, Some("@")),
+            (Some(Keycode::Q), Mod::MODEMOD, Some("@"), true, Some("@")),
+            // Right Alt which didn't change the character is still Meta.
+            (Some(Keycode::A), Mod::RALTMOD, Some("a"), true, Some("<M-a>")),
+            (Some(Keycode::A), Mod::RALTMOD, None, true, Some("<M-a>")),
+        ]);
+    }
+
+    #[test]
+    fn left_alt_is_meta_only_when_asked() {
+        check(&[
+            (Some(Keycode::A), Mod::LALTMOD, Some("å"), true, Some("<M-a>")),
+            (Some(Keycode::A), Mod::LALTMOD, Some("å"), false, Some("å")),
+            (Some(Keycode::A), Mod::LALTMOD, None, true, Some("<M-a>")),
+            // Keys which type nothing with Alt held are still shortcuts.
+            (Some(Keycode::A), Mod::LALTMOD, None, false, Some("<M-a>")),
+            (Some(Keycode::Left), Mod::LALTMOD, None, false, Some("<M-Left>")),
+        ]);
     }
 
-    result
+    enum Input {
+        Down(Keycode, Mod),
+        Text(&'static str),
+        Other
+    }
+
+    // Feeds a poll's worth of events through the pairing, flushing at the end like the ui loop.
+    fn pair(text_input: bool, inputs: &[Input]) -> Vec<KeyPress> {
+        let mut pairing = KeyPairing::new(text_input);
+        let mut key_presses = Vec::new();
+        for input in inputs {
+            key_presses.extend(match input {
+                Input::Down(keycode, modifiers) => pairing.key_down(*keycode, *modifiers),
+                Input::Text(text) => Some(pairing.text_input(text.to_string())),
+                Input::Other => pairing.flush()
+            });
+        }
+        key_presses.extend(pairing.flush());
+        key_presses
+    }
+
+    fn press(keycode: Option<Keycode>, modifiers: Mod, text: Option<&str>) -> KeyPress {
+        KeyPress { keycode, modifiers, text: text.map(String::from) }
+    }
+
+    #[test]
+    fn keys_are_paired_with_the_text_which_follows_them() {
+        assert_eq!(
+            pair(true, &[Input::Down(Keycode::A, Mod::LSHIFTMOD), Input::Text("A")]),
+            vec![press(Some(Keycode::A), Mod::LSHIFTMOD, Some("A"))]);
+    }
+
+    #[test]
+    fn keys_without_text_are_sent_when_something_else_happens() {
+        assert_eq!(
+            pair(true, &[Input::Down(Keycode::Left, Mod::NOMOD), Input::Other, Input::Down(Keycode::A, Mod::NOMOD), Input::Text("a")]),
+            vec![press(Some(Keycode::Left), Mod::NOMOD, None), press(Some(Keycode::A), Mod::NOMOD, Some("a"))]);
+        assert_eq!(
+            pair(true, &[Input::Down(Keycode::Left, Mod::NOMOD), Input::Down(Keycode::Right, Mod::NOMOD)]),
+            vec![press(Some(Keycode::Left), Mod::NOMOD, None), press(Some(Keycode::Right), Mod::NOMOD, None)]);
+    }
+
+    #[test]
+    fn keys_without_text_are_sent_at_the_end_of_the_events() {
+        assert_eq!(
+            pair(true, &[Input::Down(Keycode::W, Mod::LCTRLMOD)]),
+            vec![press(Some(Keycode::W), Mod::LCTRLMOD, None)]);
+    }
+
+    #[test]
+    fn text_without_a_key_comes_through_on_its_own() {
+        assert_eq!(
+            pair(true, &[Input::Text("日本"), Input::Down(Keycode::A, Mod::NOMOD), Input::Text("a"), Input::Text("b")]),
+            vec![
+                press(None, Mod::NOMOD, Some("日本")),
+                press(Some(Keycode::A), Mod::NOMOD, Some("a")),
+                press(None, Mod::NOMOD, Some("b"))
+            ]);
+    }
+
+    #[test]
+    fn repeated_keys_are_paired_each_time() {
+        let repeats = [
+            Input::Down(Keycode::J, Mod::NOMOD), Input::Text("j"),
+            Input::Down(Keycode::J, Mod::NOMOD), Input::Text("j"),
+            Input::Down(Keycode::J, Mod::NOMOD), Input::Text("j")
+        ];
+        assert_eq!(pair(true, &repeats), vec![press(Some(Keycode::J), Mod::NOMOD, Some("j")); 3]);
+
+        let repeats = [Input::Down(Keycode::Down, Mod::NOMOD), Input::Down(Keycode::Down, Mod::NOMOD), Input::Down(Keycode::Down, Mod::NOMOD)];
+        assert_eq!(pair(true, &repeats), vec![press(Some(Keycode::Down), Mod::NOMOD, None); 3]);
+    }
+
+    #[test]
+    fn keys_are_sent_right_away_without_text_input() {
+        assert_eq!(
+            pair(false, &[Input::Down(Keycode::J, Mod::NOMOD), Input::Down(Keycode::Semicolon, Mod::LSHIFTMOD), Input::Down(Keycode::Escape, Mod::NOMOD)]),
+            vec![
+                press(Some(Keycode::J), Mod::NOMOD, Some("j")),
+                press(Some(Keycode::Semicolon), Mod::LSHIFTMOD, Some(":")),
+                press(Some(Keycode::Escape), Mod::NOMOD, None)
+            ]);
+    }
 }
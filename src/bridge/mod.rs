@@ -1,23 +1,35 @@
 mod events;
 mod handler;
+mod clipboard;
 mod keybindings;
 mod ui_commands;
+#[cfg(test)]
+mod test_neovim;
 
 use std::sync::Arc;
 use std::process::Stdio;
 
 use rmpv::Value;
-use nvim_rs::{create::tokio as create, UiAttachOptions};
+use nvim_rs::{Neovim, UiAttachOptions, create::tokio as create};
+use nvim_rs::error::LoopError;
+use futures::io::AsyncWrite;
 use tokio::runtime::Runtime;
 use tokio::process::Command;
+use tokio::task::{JoinHandle, JoinError};
 use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use parking_lot::Mutex;
 use log::{info, error, trace};
 
 pub use events::*;
 pub use keybindings::*;
 pub use ui_commands::UiCommand;
+pub use clipboard::handle_clipboard_requests;
+use clipboard::register_clipboard_provider;
+use ui_commands::coalesce_commands;
 use handler::NeovimHandler;
+use crate::editor::EDITOR;
 use crate::error_handling::ResultPanicExplanation;
+use crate::redraw_scheduler::REDRAW_SCHEDULER;
 use crate::settings::SETTINGS;
 use crate::INITIAL_DIMENSIONS;
 
@@ -26,6 +38,11 @@ lazy_static! {
     pub static ref BRIDGE: Bridge = Bridge::new();
 }
 
+// Writer half of whatever transport the neovim instance is connected over. Embedded instances
+// write to the child's stdin, remote instances to a tcp stream or unix socket.
+pub trait NeovimWriter: AsyncWrite + Send + Unpin + 'static {}
+impl<W: AsyncWrite + Send + Unpin + 'static> NeovimWriter for W {}
+
 #[cfg(target_os = "windows")]
 fn set_windows_creation_flags(cmd: &mut Command) {
     cmd.creation_flags(0x08000000); // CREATE_NO_WINDOW
@@ -44,6 +61,18 @@ fn create_nvim_command() -> Command {
     cmd
 }
 
+// Addresses passed to nvim --listen are either host:port pairs or paths to a unix socket.
+fn is_tcp_address(address: &str) -> bool {
+    if address.contains('/') || address.contains('\\') {
+        return false;
+    }
+
+    match address.rfind(':') {
+        Some(separator) => address[separator + 1..].parse::<u16>().is_ok(),
+        None => false
+    }
+}
+
 async fn drain(receiver: &mut UnboundedReceiver<UiCommand>) -> Option<Vec<UiCommand>> {
     if let Some(ui_command) = receiver.recv().await {
         let mut results = vec![ui_command];
@@ -56,94 +85,310 @@ async fn drain(receiver: &mut UnboundedReceiver<UiCommand>) -> Option<Vec<UiComm
     }
 }
 
-async fn start_process(mut receiver: UnboundedReceiver<UiCommand>) {
-    let (width, height) = INITIAL_DIMENSIONS;
-    let (mut nvim, io_handler, _) = create::new_child_cmd(&mut create_nvim_command(), NeovimHandler()).await
-        .unwrap_or_explained_panic("Could not locate or start the neovim process");
-
-    tokio::spawn(async move {
-        info!("Close watcher started");
-        match io_handler.await {
-            Err(join_error) => eprintln!("Error joining IO loop: '{}'", join_error),
-            Ok(Err(error)) => {
-                if !error.is_channel_closed() {
-                    error!("Error: '{}'", error);
-                }
-            },
-            Ok(Ok(())) => {}
+async fn start_process(
+        mut receiver: UnboundedReceiver<UiCommand>, mut reconnect_receiver: UnboundedReceiver<()>,
+        status: Arc<Mutex<ConnectionStatus>>) {
+    loop {
+        let reason = match &SETTINGS.server_address {
+            Some(address) => connect_to_server(address, &mut receiver, &status).await,
+            None => start_embedded_process(&mut receiver, &status).await
         };
-        std::process::exit(0);
-    });
 
-    if let Ok(Value::Integer(correct_version)) = nvim.eval("has(\"nvim-0.4\")").await {
-        if correct_version.as_i64() != Some(1) {
-            eprintln!("Neovide requires version 0.4 or higher");
-            std::process::exit(0);
+        error!("Neovim connection ended: {}", reason);
+        while reconnect_receiver.try_recv().is_ok() {}
+        *status.lock() = ConnectionStatus::Disconnected(reason);
+        REDRAW_SCHEDULER.queue_next_frame();
+
+        if reconnect_receiver.recv().await.is_none() {
+            break;
+        }
+        info!("Reconnecting to neovim");
+    }
+}
+
+async fn start_embedded_process(receiver: &mut UnboundedReceiver<UiCommand>, status: &Mutex<ConnectionStatus>) -> String {
+    let (nvim, io_handler, mut child) = match create::new_child_cmd(&mut create_nvim_command(), NeovimHandler::new()).await {
+        Ok(connection) => connection,
+        Err(error) => return format!("Could not locate or start the neovim process: {}", error)
+    };
+
+    if let Err(reason) = run_session(nvim, io_handler, receiver, status).await {
+        child.kill().ok();
+        return reason;
+    }
+
+    match child.await {
+        Ok(exit_status) if exit_status.success() => std::process::exit(0),
+        Ok(exit_status) => match exit_status.code() {
+            Some(code) => format!("Neovim exited with status {}", code),
+            None => format!("Neovim was terminated ({})", exit_status)
+        },
+        Err(error) => format!("Could not read the exit status of neovim: {}", error)
+    }
+}
+
+async fn connect_to_server(address: &str, receiver: &mut UnboundedReceiver<UiCommand>, status: &Mutex<ConnectionStatus>) -> String {
+    info!("Connecting to neovim server at {}", address);
+    let result = if is_tcp_address(address) {
+        match create::new_tcp(address, NeovimHandler::new()).await {
+            Ok((nvim, io_handler)) => run_session(nvim, io_handler, receiver, status).await,
+            Err(error) => Err(format!("Could not connect to neovim server at {}: {}", address, error))
         }
     } else {
-        eprintln!("Neovide requires version 0.4 or higher");
-        std::process::exit(0);
+        connect_to_unix_socket(address, receiver, status).await
+    };
+
+    match result {
+        Ok(reason) => format!("Lost connection to {}: {}", address, reason),
+        Err(reason) => reason
+    }
+}
+
+#[cfg(unix)]
+async fn connect_to_unix_socket(path: &str, receiver: &mut UnboundedReceiver<UiCommand>, status: &Mutex<ConnectionStatus>) -> Result<String, String> {
+    match create::new_unix_socket(path, NeovimHandler::new()).await {
+        Ok((nvim, io_handler)) => run_session(nvim, io_handler, receiver, status).await,
+        Err(error) => Err(format!("Could not connect to neovim socket at {}: {}", path, error))
+    }
+}
+
+#[cfg(not(unix))]
+async fn connect_to_unix_socket(path: &str, _receiver: &mut UnboundedReceiver<UiCommand>, _status: &Mutex<ConnectionStatus>) -> Result<String, String> {
+    Err(format!("Could not connect to {}: only tcp addresses of the form host:port are supported on this platform", path))
+}
+
+fn describe_io_result(io_result: Result<Result<(), Box<LoopError>>, JoinError>) -> String {
+    match io_result {
+        Err(join_error) => format!("Error joining IO loop: {}", join_error),
+        Ok(Err(error)) if !error.is_channel_closed() => format!("Error in IO loop: {}", error),
+        _ => "The connection was closed".to_string()
+    }
+}
+
+// Runs a single connection until the io loop finishes. Returns Ok with the reason the connection
+// ended, or Err if the instance could not be set up in the first place.
+async fn run_session<W: NeovimWriter>(
+        nvim: Neovim<W>, io_handler: JoinHandle<Result<(), Box<LoopError>>>,
+        receiver: &mut UnboundedReceiver<UiCommand>, status: &Mutex<ConnectionStatus>) -> Result<String, String> {
+    let session = async {
+        setup_neovim_instance(&nvim).await?;
+        *status.lock() = ConnectionStatus::Connected;
+        REDRAW_SCHEDULER.queue_next_frame();
+
+        process_commands(receiver, &nvim).await;
+        Ok::<String, String>("The UI command channel was closed".to_string())
+    };
+
+    tokio::select! {
+        io_result = io_handler => Ok(describe_io_result(io_result)),
+        session_result = session => session_result
+    }
+}
+
+async fn setup_neovim_instance<W: NeovimWriter>(nvim: &Neovim<W>) -> Result<(), String> {
+    let (width, height) = INITIAL_DIMENSIONS;
+
+    match nvim.eval("has(\"nvim-0.4\")").await {
+        Ok(Value::Integer(correct_version)) if correct_version.as_i64() == Some(1) => {},
+        _ => return Err("Neovide requires version 0.4 or higher".to_string())
     };
 
+    // Embedded instances always talk to us over channel 1, but a server may have any number of
+    // clients attached, so notifications have to be addressed to the channel we were given.
+    let channel = nvim.get_api_info().await
+        .map_err(|error| format!("Could not read api info from neovim process: {}", error))?
+        .get(0)
+        .and_then(|channel| channel.as_u64())
+        .ok_or_else(|| "Could not read channel id from neovim api info".to_string())?;
+
     nvim.set_var("neovide", Value::Boolean(true)).await
-        .unwrap_or_explained_panic("Could not communicate with neovim process");
+        .map_err(|error| format!("Could not communicate with neovim process: {}", error))?;
+
+    register_clipboard_provider(nvim, channel).await
+        .map_err(|error| format!("Could not register clipboard provider: {}", error))?;
+
+    // Settings have to be read before attaching since they decide which ui extensions to ask for.
+    SETTINGS.read_initial_values(nvim).await;
+
+    // Neovim only supports external messages alongside an external command line.
+    let ext_messages = SETTINGS.get("ext_messages").read_bool();
+    let ext_cmdline = SETTINGS.get("ext_cmdline").read_bool() || ext_messages;
+    let ext_popupmenu = SETTINGS.get("ext_popupmenu").read_bool();
+    let ext_tabline = SETTINGS.get("ext_tabline").read_bool();
+    let ext_multigrid = SETTINGS.get("ext_multigrid").read_bool();
+
     let mut options = UiAttachOptions::new();
     options.set_linegrid_external(true);
+    options.set_cmdline_external(ext_cmdline);
+    options.set_messages_external(ext_messages);
+    options.set_popupmenu_external(ext_popupmenu);
+    options.set_tabline_external(ext_tabline);
+    options.set_multigrid_external(ext_multigrid);
     options.set_rgb(true);
+    {
+        let mut editor = EDITOR.lock();
+        editor.reset();
+        editor.command_line.external = ext_cmdline;
+    }
     nvim.ui_attach(width as i64, height as i64, &options).await
-        .unwrap_or_explained_panic("Could not attach ui to neovim process");
+        .map_err(|error| format!("Could not attach ui to neovim process: {}", error))?;
     info!("Neovim process attached");
 
-    let nvim = Arc::new(nvim);
-    let input_nvim = nvim.clone();
-    tokio::spawn(async move {
-        info!("UiCommand processor started");
-        while let Some(commands) = drain(&mut receiver).await {
-            let (resize_list, other_commands): (Vec<UiCommand>, Vec<UiCommand>) = commands
-                .into_iter()
-                .partition(|command| command.is_resize());
-
-            for command in resize_list
-                .into_iter().last().into_iter()
-                .chain(other_commands.into_iter()) {
-
-                let input_nvim = input_nvim.clone();
-                tokio::spawn(async move {
-                    trace!("Executing UiCommand: {:?}", &command);
-                    command.execute(&input_nvim).await;
-                });
-            }
-        }
-    });
-
-    SETTINGS.read_initial_values(&nvim).await;
-    SETTINGS.setup_changed_listeners(&nvim).await;
+    SETTINGS.setup_changed_listeners(nvim, channel).await;
 
     nvim.set_option("lazyredraw", Value::Boolean(false)).await
         .ok();
+
+    Ok(())
+}
+
+// Commands are executed one at a time so that input reaches neovim in exactly the order it was
+// queued in.
+async fn process_commands<W: NeovimWriter>(receiver: &mut UnboundedReceiver<UiCommand>, nvim: &Neovim<W>) {
+    info!("UiCommand processor started");
+    while let Some(commands) = drain(receiver).await {
+        for command in coalesce_commands(commands) {
+            let name = command.name();
+            trace!("Executing UiCommand: {}", name);
+            if let Err(error) = command.execute(nvim).await {
+                error!("UiCommand {} failed: {}", name, error);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConnectionStatus {
+    Connecting,
+    Connected,
+    Disconnected(String)
 }
 
 pub struct Bridge {
     _runtime: Runtime, // Necessary to keep runtime running
-    sender: UnboundedSender<UiCommand>
+    sender: UnboundedSender<UiCommand>,
+    reconnect_sender: UnboundedSender<()>,
+    status: Arc<Mutex<ConnectionStatus>>
 }
 
 impl Bridge {
     pub fn new() -> Bridge {
         let runtime = Runtime::new().unwrap();
         let (sender, receiver) = unbounded_channel::<UiCommand>();
+        let (reconnect_sender, reconnect_receiver) = unbounded_channel::<()>();
+        let status = Arc::new(Mutex::new(ConnectionStatus::Connecting));
 
+        let process_status = status.clone();
         runtime.spawn(async move {
-            start_process(receiver).await;
+            start_process(receiver, reconnect_receiver, process_status).await;
         });
 
-        Bridge { _runtime: runtime, sender }
+        Bridge { _runtime: runtime, sender, reconnect_sender, status }
+    }
+
+    pub fn connection_status(&self) -> ConnectionStatus {
+        self.status.lock().clone()
+    }
+
+    pub fn reconnect(&self) {
+        let mut status = self.status.lock();
+        if let ConnectionStatus::Disconnected(_) = *status {
+            *status = ConnectionStatus::Connecting;
+            self.reconnect_sender.send(())
+                .unwrap_or_explained_panic("Could not send reconnect request to the neovim process.");
+        }
     }
 
     pub fn queue_command(&self, command: UiCommand) {
-        trace!("UiCommand queued: {:?}", &command);
+        if let ConnectionStatus::Disconnected(_) = *self.status.lock() {
+            trace!("UiCommand dropped while disconnected: {}", command.name());
+            return;
+        }
+
+        trace!("UiCommand queued: {}", command.name());
         self.sender.send(command)
             .unwrap_or_explained_panic(
                 "Could not send UI command from the window system to the neovim process.");
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::editor::DEFAULT_GRID;
+    use test_neovim::FakeNeovim;
+
+    fn run_commands(neovim: &FakeNeovim, commands: Vec<UiCommand>) {
+        let mut runtime = Runtime::new().unwrap();
+        let (nvim, io) = neovim.connect();
+        runtime.block_on(async move {
+            tokio::spawn(io);
+
+            let (sender, mut receiver) = unbounded_channel();
+            for command in commands {
+                sender.send(command).unwrap();
+            }
+            drop(sender);
+            process_commands(&mut receiver, &nvim).await;
+        });
+    }
+
+    #[test]
+    fn commands_reach_neovim_in_the_order_they_were_queued() {
+        let neovim = FakeNeovim::new();
+        run_commands(&neovim, vec![
+            UiCommand::Keyboard("a".to_string()),
+            UiCommand::MouseButton { action: "press".to_string(), grid_id: DEFAULT_GRID, position: (1, 2) },
+            UiCommand::Drag { grid_id: DEFAULT_GRID, position: (3, 2) },
+            UiCommand::Keyboard("b".to_string()),
+            UiCommand::Scroll { direction: "down".to_string(), grid_id: DEFAULT_GRID, position: (3, 2) },
+            UiCommand::Keyboard("c".to_string())
+        ]);
+
+        let calls = neovim.calls();
+        let summary: Vec<(&str, &Value)> = calls.iter()
+            .map(|(method, arguments)| (method.as_str(), &arguments[0]))
+            .collect();
+        assert_eq!(summary, vec![
+            ("nvim_input", &Value::from("a")),
+            ("nvim_input_mouse", &Value::from("left")),
+            ("nvim_input_mouse", &Value::from("left")),
+            ("nvim_input", &Value::from("b")),
+            ("nvim_input_mouse", &Value::from("wheel")),
+            ("nvim_input", &Value::from("c"))
+        ]);
+        assert_eq!(calls[2].1[1], Value::from("drag"));
+    }
+
+    #[test]
+    fn batched_commands_are_coalesced() {
+        let neovim = FakeNeovim::new();
+        run_commands(&neovim, vec![
+            UiCommand::Resize { width: 80, height: 24 },
+            UiCommand::Keyboard("a".to_string()),
+            UiCommand::Keyboard("b".to_string()),
+            UiCommand::Resize { width: 100, height: 30 }
+        ]);
+
+        assert_eq!(neovim.calls(), vec![
+            ("nvim_input".to_string(), vec![Value::from("ab")]),
+            ("nvim_ui_try_resize".to_string(), vec![Value::from(100), Value::from(30)])
+        ]);
+    }
+
+    #[test]
+    fn failed_commands_dont_stop_the_ones_after_them() {
+        let neovim = FakeNeovim::new();
+        neovim.fail("nvim_ui_try_resize");
+        run_commands(&neovim, vec![
+            UiCommand::Keyboard("a".to_string()),
+            UiCommand::Resize { width: 100, height: 30 },
+            UiCommand::Drag { grid_id: DEFAULT_GRID, position: (3, 2) },
+            UiCommand::Keyboard("b".to_string())
+        ]);
+
+        assert_eq!(neovim.method_names(), vec![
+            "nvim_input", "nvim_ui_try_resize", "nvim_input_mouse", "nvim_input"
+        ]);
+    }
+}
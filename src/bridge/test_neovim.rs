@@ -0,0 +1,145 @@
+use std::io::{self, Cursor};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, Waker};
+
+use futures::io::{AsyncRead, AsyncWrite};
+use nvim_rs::Neovim;
+use nvim_rs::error::LoopError;
+use parking_lot::Mutex;
+use rmpv::Value;
+
+use super::handler::NeovimHandler;
+
+const REQUEST: u64 = 0;
+const RESPONSE: u64 = 1;
+const NOTIFICATION: u64 = 2;
+
+#[derive(Default)]
+struct FakeState {
+    // Bytes written by the client which don't make up a whole message yet.
+    unparsed: Vec<u8>,
+    responses: Vec<u8>,
+    reader: Option<Waker>,
+    calls: Vec<(String, Vec<Value>)>,
+    failing_methods: Vec<String>
+}
+
+impl FakeState {
+    fn answer(&mut self, message: Value) {
+        let fields = match message {
+            Value::Array(fields) => fields,
+            _ => return
+        };
+
+        match fields.as_slice() {
+            [kind, id, method, Value::Array(arguments)] if kind.as_u64() == Some(REQUEST) => {
+                let method = method.as_str().unwrap_or_default().to_string();
+                let (error, result) = if self.failing_methods.contains(&method) {
+                    (Value::Array(vec![Value::from(0), Value::from("Failed on purpose")]), Value::Nil)
+                } else {
+                    (Value::Nil, result_for(&method, arguments))
+                };
+                let response = Value::Array(vec![Value::from(RESPONSE), id.clone(), error, result]);
+                rmpv::encode::write_value(&mut self.responses, &response).unwrap();
+                self.calls.push((method, arguments.clone()));
+
+                if let Some(reader) = self.reader.take() {
+                    reader.wake();
+                }
+            },
+            [kind, method, Value::Array(arguments)] if kind.as_u64() == Some(NOTIFICATION) => {
+                self.calls.push((method.as_str().unwrap_or_default().to_string(), arguments.clone()));
+            },
+            _ => {}
+        }
+    }
+}
+
+// Plausible results for the calls the ui commands make, so that the client can decode them.
+fn result_for(method: &str, arguments: &[Value]) -> Value {
+    match method {
+        "nvim_input" => {
+            let length = arguments.get(0).and_then(Value::as_str).map(str::len).unwrap_or(0);
+            Value::from(length as u64)
+        },
+        "nvim_paste" => Value::from(true),
+        _ => Value::Nil
+    }
+}
+
+// Stands in for a neovim instance at the other end of the connection. Every request is answered
+// straight away and recorded along with notifications in the order they arrived.
+#[derive(Clone, Default)]
+pub struct FakeNeovim(Arc<Mutex<FakeState>>);
+
+impl FakeNeovim {
+    pub fn new() -> FakeNeovim {
+        FakeNeovim::default()
+    }
+
+    // Answers requests for the method with an error instead.
+    pub fn fail(&self, method: &str) {
+        self.0.lock().failing_methods.push(method.to_string());
+    }
+
+    pub fn calls(&self) -> Vec<(String, Vec<Value>)> {
+        self.0.lock().calls.clone()
+    }
+
+    pub fn method_names(&self) -> Vec<String> {
+        self.calls().into_iter().map(|(method, _)| method).collect()
+    }
+
+    // Connects a client to the fake. The io future has to be spawned for calls to complete.
+    pub fn connect(&self) -> (Neovim<FakeWriter>, impl std::future::Future<Output = Result<(), Box<LoopError>>>) {
+        Neovim::new(FakeReader(self.clone()), FakeWriter(self.clone()), NeovimHandler::new())
+    }
+}
+
+pub struct FakeReader(FakeNeovim);
+
+impl AsyncRead for FakeReader {
+    fn poll_read(self: Pin<&mut Self>, context: &mut Context, buffer: &mut [u8]) -> Poll<io::Result<usize>> {
+        let mut state = (self.0).0.lock();
+        if state.responses.is_empty() {
+            state.reader = Some(context.waker().clone());
+            return Poll::Pending;
+        }
+
+        let length = buffer.len().min(state.responses.len());
+        buffer[..length].copy_from_slice(&state.responses[..length]);
+        state.responses.drain(..length);
+        Poll::Ready(Ok(length))
+    }
+}
+
+pub struct FakeWriter(FakeNeovim);
+
+impl AsyncWrite for FakeWriter {
+    fn poll_write(self: Pin<&mut Self>, _context: &mut Context, buffer: &[u8]) -> Poll<io::Result<usize>> {
+        let mut state = (self.0).0.lock();
+        state.unparsed.extend_from_slice(buffer);
+
+        loop {
+            let mut cursor = Cursor::new(&state.unparsed[..]);
+            let message = match rmpv::decode::read_value(&mut cursor) {
+                Ok(message) => message,
+                Err(_) => break
+            };
+            let used = cursor.position() as usize;
+            state.unparsed.drain(..used);
+            state.answer(message);
+        }
+
+        Poll::Ready(Ok(buffer.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _context: &mut Context) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _context: &mut Context) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
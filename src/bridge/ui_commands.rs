@@ -1,35 +1,165 @@
-use nvim_rs::Neovim;
-use nvim_rs::compat::tokio::Compat;
-use tokio::process::ChildStdin;
+use rmpv::Value;
+use nvim_rs::{Neovim, Tabpage};
+use nvim_rs::error::CallError;
 
-#[derive(Debug, Clone)]
+use crate::editor::DEFAULT_GRID;
+use crate::settings::SETTINGS;
+use super::NeovimWriter;
+
+// Neovim expects mouse input on the default grid to be addressed as grid 0, which is also the only
+// grid it accepts when ext_multigrid is off.
+fn mouse_grid(grid_id: u64) -> i64 {
+    if grid_id == DEFAULT_GRID {
+        0
+    } else {
+        grid_id as i64
+    }
+}
+
+// Neovim versions without nvim_ui_set_focus reject the call, in which case the autocommands it
+// would have fired are fired directly instead.
+async fn set_focus<W: NeovimWriter>(nvim: &Neovim<W>, gained: bool) -> Result<(), Box<CallError>> {
+    match nvim.call("nvim_ui_set_focus", vec![Value::from(gained)]).await? {
+        Ok(_) => Ok(()),
+        Err(_) => {
+            let event = if gained { "FocusGained" } else { "FocusLost" };
+            nvim.command(&format!("doautocmd <nomodeline> {}", event)).await
+        }
+    }
+}
+
+// Opens the files in one go, each according to the drop_file_action setting. Plain edits load
+// every file as a buffer and show the first, since only one of them can take the window.
+async fn open_dropped_files<W: NeovimWriter>(nvim: &Neovim<W>, paths: Vec<String>) -> Result<(), Box<CallError>> {
+    let mut escaped_paths = Vec::with_capacity(paths.len());
+    for path in paths {
+        let escaped_path = nvim.call_function("fnameescape", vec![Value::from(path)]).await?;
+        if let Some(escaped_path) = escaped_path.as_str() {
+            escaped_paths.push(escaped_path.to_string());
+        }
+    }
+
+    let first_path = match escaped_paths.first() {
+        Some(first_path) => first_path.clone(),
+        None => return Ok(())
+    };
+
+    let command = match SETTINGS.get("drop_file_action").read_string().as_ref() {
+        "argadd" => format!("argadd {}", escaped_paths.join(" ")),
+        action @ "tabedit" | action @ "split" | action @ "vsplit" => escaped_paths.iter()
+            .map(|path| format!("{} {}", action, path))
+            .collect::<Vec<String>>()
+            .join(" | "),
+        _ => format!("badd {} | edit {}", escaped_paths.join(" | badd "), first_path)
+    };
+    nvim.command(&command).await
+}
+
+// Large pastes are sent in pieces so that neovim can redraw and the user can cancel in between.
+const PASTE_CHUNK_SIZE: usize = 4096;
+
+// Streams text to nvim_paste using its phase protocol: 1 for the first chunk, 2 for the ones in
+// the middle and 3 for the last, or -1 when everything fits in a single call.
+async fn paste<W: NeovimWriter>(nvim: &Neovim<W>, text: &str) -> Result<(), Box<CallError>> {
+    let mut chunks = Vec::new();
+    let mut remaining = text;
+    while remaining.len() > PASTE_CHUNK_SIZE {
+        let mut split = PASTE_CHUNK_SIZE;
+        while !remaining.is_char_boundary(split) {
+            split -= 1;
+        }
+        let (chunk, rest) = remaining.split_at(split);
+        chunks.push(chunk);
+        remaining = rest;
+    }
+    chunks.push(remaining);
+
+    let last_index = chunks.len() - 1;
+    for (index, chunk) in chunks.into_iter().enumerate() {
+        let phase = match index {
+            _ if last_index == 0 => -1,
+            0 => 1,
+            _ if index == last_index => 3,
+            _ => 2
+        };
+
+        // Neovim returns false when the paste was cancelled, in which case the rest is dropped.
+        if !nvim.paste(chunk, true, phase).await? {
+            break;
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum UiCommand {
     Resize { width: u32, height: u32 },
+    ResizeGrid { grid_id: u64, width: u32, height: u32 },
     Keyboard(String),
-    MouseButton { action: String, position: (u32, u32) },
-    Scroll { direction: String, position: (u32, u32) },
-    Drag(u32, u32)
+    MouseButton { action: String, grid_id: u64, position: (u32, u32) },
+    Scroll { direction: String, grid_id: u64, position: (u32, u32) },
+    Drag { grid_id: u64, position: (u32, u32) },
+    SwitchTab(Value),
+    CloseTab(Value),
+    CloseWindow(u64),
+    FocusGained,
+    FocusLost,
+    DropFiles(Vec<String>),
+    DropText(String),
+    Paste(String)
 }
 
 impl UiCommand {
-    pub async fn execute(self, nvim: &Neovim<Compat<ChildStdin>>) {
+    pub async fn execute<W: NeovimWriter>(self, nvim: &Neovim<W>) -> Result<(), Box<CallError>> {
         match self {
             UiCommand::Resize { width, height } => 
-                nvim.ui_try_resize(width.max(10) as i64, height.max(3) as i64).await
-                    .expect("Resize failed"),
-            UiCommand::Keyboard(input_command) => { 
-                nvim.input(&input_command).await
-                    .expect("Input failed"); 
+                nvim.ui_try_resize(width.max(10) as i64, height.max(3) as i64).await,
+            UiCommand::ResizeGrid { grid_id, width, height } =>
+                nvim.ui_try_resize_grid(grid_id as i64, width.max(1) as i64, height.max(1) as i64).await,
+            UiCommand::Keyboard(input_command) => 
+                nvim.input(&input_command).await.map(|_| ()),
+            UiCommand::MouseButton { action, grid_id, position: (grid_x, grid_y) } => 
+                nvim.input_mouse("left", &action, "", mouse_grid(grid_id), grid_y as i64, grid_x as i64).await,
+            UiCommand::Scroll { direction, grid_id, position: (grid_x, grid_y) } => 
+                nvim.input_mouse("wheel", &direction, "", mouse_grid(grid_id), grid_y as i64, grid_x as i64).await,
+            UiCommand::Drag { grid_id, position: (grid_x, grid_y) } =>
+                nvim.input_mouse("left", "drag", "", mouse_grid(grid_id), grid_y as i64, grid_x as i64).await,
+            UiCommand::SwitchTab(tab) =>
+                nvim.set_current_tabpage(&Tabpage::new(tab, nvim.clone())).await,
+            UiCommand::CloseTab(tab) => {
+                // :tabclose takes a tab number rather than a handle, and the number shifts as tabs
+                // are opened and closed, so it is looked up right before closing.
+                let tab_number = Tabpage::new(tab, nvim.clone()).get_number().await?;
+                nvim.command(&format!("tabclose {}", tab_number)).await
             },
-            UiCommand::MouseButton { action, position: (grid_x, grid_y) } => 
-                nvim.input_mouse("left", &action, "", 0, grid_y as i64, grid_x as i64).await
-                    .expect("Mouse Input Failed"),
-            UiCommand::Scroll { direction, position: (grid_x, grid_y) } => 
-                nvim.input_mouse("wheel", &direction, "", 0, grid_y as i64, grid_x as i64).await
-                    .expect("Mouse Scroll Failed"),
-            UiCommand::Drag(grid_x, grid_y) =>
-                nvim.input_mouse("left", "drag", "", 0, grid_y as i64, grid_x as i64).await
-                    .expect("Mouse Drag Failed")
+            UiCommand::CloseWindow(window_handle) =>
+                nvim.command(&format!("call nvim_win_close({}, v:false)", window_handle)).await,
+            UiCommand::FocusGained => set_focus(nvim, true).await,
+            UiCommand::FocusLost => set_focus(nvim, false).await,
+            UiCommand::DropFiles(paths) => open_dropped_files(nvim, paths).await,
+            UiCommand::DropText(text) =>
+                nvim.paste(&text, true, -1).await.map(|_| ()),
+            UiCommand::Paste(text) => paste(nvim, &text).await
+        }
+    }
+
+    // Short name for logs. The full debug output of a paste or drop can be megabytes long.
+    pub fn name(&self) -> &'static str {
+        match self {
+            UiCommand::Resize { .. } => "Resize",
+            UiCommand::ResizeGrid { .. } => "ResizeGrid",
+            UiCommand::Keyboard(_) => "Keyboard",
+            UiCommand::MouseButton { .. } => "MouseButton",
+            UiCommand::Scroll { .. } => "Scroll",
+            UiCommand::Drag { .. } => "Drag",
+            UiCommand::SwitchTab(_) => "SwitchTab",
+            UiCommand::CloseTab(_) => "CloseTab",
+            UiCommand::CloseWindow(_) => "CloseWindow",
+            UiCommand::FocusGained => "FocusGained",
+            UiCommand::FocusLost => "FocusLost",
+            UiCommand::DropFiles(_) => "DropFiles",
+            UiCommand::DropText(_) => "DropText",
+            UiCommand::Paste(_) => "Paste"
         }
     }
 
@@ -40,3 +170,85 @@ impl UiCommand {
         }
     }
 }
+
+// Reduces a batch of queued commands to the minimum set of calls without changing the order in
+// which input reaches neovim. Only the most recent resize matters, and runs of keyboard input can
+// be sent with a single nvim_input call.
+pub fn coalesce_commands(commands: Vec<UiCommand>) -> Vec<UiCommand> {
+    let last_resize = commands.iter().rposition(UiCommand::is_resize);
+
+    let mut coalesced: Vec<UiCommand> = Vec::with_capacity(commands.len());
+    for (index, command) in commands.into_iter().enumerate() {
+        if command.is_resize() && Some(index) != last_resize {
+            continue;
+        }
+
+        if let UiCommand::Keyboard(input) = &command {
+            if let Some(UiCommand::Keyboard(previous_input)) = coalesced.last_mut() {
+                previous_input.push_str(input);
+                continue;
+            }
+        }
+
+        coalesced.push(command);
+    }
+
+    coalesced
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keyboard(input: &str) -> UiCommand {
+        UiCommand::Keyboard(input.to_string())
+    }
+
+    fn drag(x: u32) -> UiCommand {
+        UiCommand::Drag { grid_id: DEFAULT_GRID, position: (x, 0) }
+    }
+
+    #[test]
+    fn consecutive_keyboard_input_is_merged() {
+        let commands = vec![keyboard("a"), keyboard("<C-w>"), keyboard("j")];
+        assert_eq!(coalesce_commands(commands), vec![keyboard("a<C-w>j")]);
+    }
+
+    #[test]
+    fn keyboard_input_is_not_merged_across_other_commands() {
+        let commands = vec![keyboard("a"), drag(1), keyboard("b"), keyboard("c"), drag(2)];
+        assert_eq!(coalesce_commands(commands), vec![keyboard("a"), drag(1), keyboard("bc"), drag(2)]);
+    }
+
+    #[test]
+    fn only_the_last_resize_is_kept_where_it_was_queued() {
+        let commands = vec![
+            UiCommand::Resize { width: 80, height: 24 },
+            keyboard("a"),
+            UiCommand::Resize { width: 100, height: 30 },
+            drag(1),
+            UiCommand::Resize { width: 120, height: 40 },
+            keyboard("b")
+        ];
+        assert_eq!(coalesce_commands(commands), vec![
+            keyboard("a"),
+            drag(1),
+            UiCommand::Resize { width: 120, height: 40 },
+            keyboard("b")
+        ]);
+    }
+
+    #[test]
+    fn grid_resizes_are_not_collapsed() {
+        let commands = vec![
+            UiCommand::ResizeGrid { grid_id: 2, width: 10, height: 5 },
+            UiCommand::ResizeGrid { grid_id: 3, width: 20, height: 5 }
+        ];
+        assert_eq!(coalesce_commands(commands.clone()), commands);
+    }
+
+    #[test]
+    fn an_empty_batch_stays_empty() {
+        assert_eq!(coalesce_commands(Vec::new()), Vec::new());
+    }
+}
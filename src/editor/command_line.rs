@@ -0,0 +1,131 @@
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
+
+use super::StyledText;
+
+// The number of cells text takes up once drawn. Wide characters such as CJK take two cells, and
+// combining marks are counted with the character they attach to.
+pub fn cell_width(text: &str) -> usize {
+    text.graphemes(true).map(|grapheme| grapheme.width().min(2)).sum()
+}
+
+#[derive(new, Debug, Clone)]
+pub struct CommandLineLevel {
+    pub content: StyledText,
+    pub position: u64,
+    pub first_character: String,
+    pub prompt: String,
+    pub indent: u64,
+    #[new(default)]
+    pub special_character: Option<(String, bool)>
+}
+
+impl CommandLineLevel {
+    // Text drawn before the editable content: the command type, any input() prompt and the
+    // indentation neovim asked for.
+    pub fn prefix(&self) -> String {
+        format!("{}{}{}", self.first_character, self.prompt, " ".repeat(self.indent as usize))
+    }
+
+    // The column the cursor sits in, counted in cells from the start of the prefix. Position is
+    // a byte offset into the content.
+    pub fn cursor_column(&self) -> u64 {
+        let content_text: String = self.content.iter().map(|(_, text)| text.as_str()).collect();
+        let position = (self.position as usize).min(content_text.len());
+        let before_cursor = content_text.get(..position).unwrap_or(&content_text);
+
+        (cell_width(&self.prefix()) + cell_width(before_cursor)) as u64
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct CommandLine {
+    pub levels: Vec<CommandLineLevel>,
+    pub block: Vec<StyledText>,
+    // Whether neovim was attached with ext_cmdline. Otherwise the command line is drawn in the
+    // last row of the grid.
+    pub external: bool
+}
+
+impl CommandLine {
+    pub fn is_visible(&self) -> bool {
+        !self.levels.is_empty()
+    }
+
+    fn level_mut(&mut self, level: u64) -> Option<&mut CommandLineLevel> {
+        (level as usize).checked_sub(1).and_then(move |index| self.levels.get_mut(index))
+    }
+
+    pub fn show(&mut self, level: u64, command_line_level: CommandLineLevel) {
+        // Levels are 1 based and showing a level replaces it along with anything nested inside
+        // of it.
+        self.levels.truncate((level as usize).max(1) - 1);
+        self.levels.push(command_line_level);
+    }
+
+    pub fn set_position(&mut self, position: u64, level: u64) {
+        if let Some(command_line_level) = self.level_mut(level) {
+            command_line_level.position = position;
+            command_line_level.special_character = None;
+        }
+    }
+
+    pub fn set_special_character(&mut self, character: String, shift: bool, level: u64) {
+        if let Some(command_line_level) = self.level_mut(level) {
+            command_line_level.special_character = Some((character, shift));
+        }
+    }
+
+    pub fn hide(&mut self) {
+        self.levels.pop();
+    }
+
+    pub fn show_block(&mut self, lines: Vec<StyledText>) {
+        self.block = lines;
+    }
+
+    pub fn append_block_line(&mut self, line: StyledText) {
+        self.block.push(line);
+    }
+
+    pub fn hide_block(&mut self) {
+        self.block.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn level(text: &str, position: u64) -> CommandLineLevel {
+        CommandLineLevel::new(vec![(None, text.to_string())], position, ":".to_string(), String::new(), 0)
+    }
+
+    #[test]
+    fn cell_width_counts_wide_characters_twice() {
+        assert_eq!(cell_width("abc"), 3);
+        assert_eq!(cell_width("日本"), 4);
+        assert_eq!(cell_width("e\u{301}"), 1);
+        assert_eq!(cell_width(""), 0);
+    }
+
+    #[test]
+    fn cursor_column_counts_cells_before_the_cursor() {
+        assert_eq!(level("echo", 2).cursor_column(), 3);
+        // Each of these characters is three bytes long and two cells wide.
+        assert_eq!(level("日本語", 6).cursor_column(), 5);
+        assert_eq!(level("a日b", 4).cursor_column(), 4);
+    }
+
+    #[test]
+    fn cursor_column_includes_the_prompt_and_indent() {
+        let level = CommandLineLevel::new(
+            vec![(None, "x".to_string())], 1, String::new(), "名前: ".to_string(), 2);
+        assert_eq!(level.cursor_column(), 6 + 2 + 1);
+    }
+
+    #[test]
+    fn cursor_column_stops_at_the_end_of_the_content() {
+        assert_eq!(level("日本", 100).cursor_column(), 5);
+    }
+}
@@ -25,6 +25,7 @@ impl CursorShape {
 
 #[derive(Default, Debug, Clone, PartialEq)]
 pub struct CursorMode {
+    pub name: Option<String>,
     pub shape: Option<CursorShape>,
     pub style_id: Option<u64>,
     pub cell_percentage: Option<f32>,
@@ -78,7 +79,7 @@ impl Cursor {
     }
 
     pub fn change_mode(&mut self, mode_index: u64, styles: &HashMap<u64, Arc<Style>>) {
-        if let Some(CursorMode { shape, style_id, cell_percentage, blinkwait, blinkon, blinkoff }) = self.mode_list.get(mode_index as usize) {
+        if let Some(CursorMode { shape, style_id, cell_percentage, blinkwait, blinkon, blinkoff, .. }) = self.mode_list.get(mode_index as usize) {
             if let Some(shape) = shape {
                 self.shape = shape.clone();
             }
@@ -0,0 +1,84 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::bridge::MessageKind;
+use super::{Style, StyledText};
+
+// Highlight groups the notifications take their accent colors from.
+#[derive(Debug, Clone, Default)]
+pub struct MessageStyles {
+    pub error: Option<Arc<Style>>,
+    pub warning: Option<Arc<Style>>,
+    pub search: Option<Arc<Style>>
+}
+
+#[derive(new, Debug, Clone)]
+pub struct Notification {
+    pub kind: MessageKind,
+    pub content: StyledText,
+    #[new(value = "Instant::now()")]
+    pub created: Instant
+}
+
+impl Notification {
+    // Prompts are waiting on an answer from the user, so they stay up until neovim clears them
+    // instead of timing out.
+    pub fn is_persistent(&self) -> bool {
+        match self.kind {
+            MessageKind::ReturnPrompt | MessageKind::Confirm | MessageKind::ConfirmSubstitute => true,
+            _ => false
+        }
+    }
+
+    pub fn expires_at(&self, timeout: Duration) -> Option<Instant> {
+        if self.is_persistent() {
+            None
+        } else {
+            Some(self.created + timeout)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Messages {
+    pub notifications: Vec<Notification>,
+    pub history: Vec<(MessageKind, StyledText)>,
+    pub show_mode: StyledText,
+    pub show_command: StyledText,
+    pub ruler: StyledText,
+    pub styles: MessageStyles
+}
+
+impl Messages {
+    pub fn show(&mut self, kind: MessageKind, content: StyledText, replace_last: bool, timeout: Duration) {
+        let now = Instant::now();
+        self.notifications.retain(|notification| {
+            notification.expires_at(timeout).map(|expiration| expiration > now).unwrap_or(true)
+        });
+
+        if replace_last {
+            self.notifications.pop();
+        }
+
+        if content.iter().any(|(_, text)| !text.is_empty()) {
+            self.notifications.push(Notification::new(kind, content));
+        }
+    }
+
+    // Neovim clears the message area far more often than a notification should disappear, so
+    // regular notifications are left to time out on their own. Clearing only dismisses the ones
+    // which were waiting on the user.
+    pub fn clear(&mut self) {
+        self.notifications.retain(|notification| !notification.is_persistent());
+        self.history.clear();
+    }
+
+    pub fn show_history(&mut self, entries: Vec<(MessageKind, StyledText)>) {
+        self.history = entries;
+    }
+
+    pub fn status_is_empty(&self) -> bool {
+        [&self.show_mode, &self.show_command, &self.ruler].iter()
+            .all(|content| content.iter().all(|(_, text)| text.trim().is_empty()))
+    }
+}
@@ -1,9 +1,15 @@
 mod cursor;
 mod style;
 mod grid;
+mod command_line;
+mod messages;
+mod popup_menu;
+mod tabline;
+mod window;
 
 use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::Duration;
 
 use parking_lot::Mutex;
 use skulpin::skia_safe::colors;
@@ -13,10 +19,20 @@ use log::trace;
 pub use cursor::{Cursor, CursorShape, CursorMode};
 pub use style::{Colors, Style};
 pub use grid::CharacterGrid;
-use crate::bridge::{GridLineCell, GuiOption, RedrawEvent};
+pub use command_line::{cell_width, CommandLine, CommandLineLevel};
+pub use messages::{Messages, MessageStyles, Notification};
+pub use popup_menu::{PopupMenu, PopupMenuStyles};
+pub use tabline::{Tabline, TablineStyles};
+pub use window::{Window, WindowKind, GridScroll};
+use crate::bridge::{GridLineCell, GuiOption, RedrawEvent, StyledContent, WindowAnchor};
 use crate::redraw_scheduler::REDRAW_SCHEDULER;
+use crate::settings::SETTINGS;
 use crate::INITIAL_DIMENSIONS;
 
+// Grid neovim draws into when ext_multigrid is off, and the grid everything else is positioned
+// relative to when it is on.
+pub const DEFAULT_GRID: u64 = 1;
+
 lazy_static! {
     pub static ref EDITOR: Arc<Mutex<Editor>> = Arc::new(Mutex::new(Editor::new()));
 }
@@ -29,41 +45,187 @@ pub struct DrawCommand {
     pub style: Option<Arc<Style>>
 }
 
+pub type StyledText = Vec<(Option<Arc<Style>>, String)>;
+
+// The cell under the cursor, which the cursor draws over itself.
+#[derive(new, Debug, Clone)]
+pub struct CursorCell {
+    pub character: String,
+    pub double_width: bool,
+    pub style: Option<Arc<Style>>,
+    // The run of text around the cursor sharing the cell's style along with how many columns
+    // before the cursor it starts, so that ligatures can be shaped as a whole.
+    pub ligature_run: String,
+    pub ligature_offset: u64
+}
+
+impl CursorCell {
+    fn empty() -> CursorCell {
+        CursorCell::new(" ".to_string(), false, None, " ".to_string(), 0)
+    }
+}
+
+// Published state of a single grid. Draw commands accumulate here until the renderer takes them.
+#[derive(Debug, Clone)]
+pub struct WindowFrame {
+    pub grid_id: u64,
+    pub window_handle: Option<u64>,
+    pub kind: WindowKind,
+    pub position: (f64, f64),
+    pub size: (u64, u64),
+    pub hidden: bool,
+    pub order: u64,
+    pub draw_commands: Vec<DrawCommand>,
+    // Scrolls which happened before the draw commands were applied.
+    pub scrolls: Vec<GridScroll>,
+    pub should_clear: bool
+}
+
+impl WindowFrame {
+    fn take(&mut self) -> WindowFrame {
+        WindowFrame {
+            grid_id: self.grid_id,
+            window_handle: self.window_handle,
+            kind: self.kind,
+            position: self.position,
+            size: self.size,
+            hidden: self.hidden,
+            order: self.order,
+            draw_commands: std::mem::take(&mut self.draw_commands),
+            scrolls: std::mem::take(&mut self.scrolls),
+            should_clear: std::mem::replace(&mut self.should_clear, false)
+        }
+    }
+}
+
+// Snapshot of the editor state as of the last flush. Redraw events are applied to the editor as
+// they arrive, but the renderer only ever reads from this so that it never draws a half applied
+// batch.
+pub struct RenderFrame {
+    // Ordered from the bottom most window to the top most one.
+    pub windows: Vec<WindowFrame>,
+    pub default_style: Arc<Style>,
+    pub cursor: Cursor,
+    pub cursor_grid: u64,
+    pub cursor_cell: CursorCell,
+    pub grid_height: u64,
+    pub command_line: CommandLine,
+    pub messages: Messages,
+    pub popup_menu: PopupMenu,
+    pub tabline: Tabline,
+    pub font_name: Option<String>,
+    pub font_size: Option<f32>,
+    // Set for a single frame after neovim rang the bell.
+    pub bell: bool,
+    // Set for a single frame after something happened which the user should come back for.
+    pub request_attention: bool
+}
+
 pub struct Editor {
-    pub grid: CharacterGrid,
+    pub windows: HashMap<u64, Window>,
+    pub mode: String,
+    pub cursor_grid: u64,
     pub title: String,
     pub font_name: Option<String>,
     pub font_size: Option<f32>,
     pub cursor: Cursor,
+    pub command_line: CommandLine,
+    pub messages: Messages,
+    pub popup_menu: PopupMenu,
+    pub tabline: Tabline,
     pub default_style: Arc<Style>,
     pub defined_styles: HashMap<u64, Arc<Style>>,
-    pub previous_style: Option<Arc<Style>>
+    pub highlight_groups: HashMap<String, u64>,
+    pub previous_style: Option<Arc<Style>>,
+    window_order: u64,
+    bell: bool,
+    request_attention: bool,
+    frame: RenderFrame
 }
 
 impl Editor {
     pub fn new() -> Editor {
-        let mut editor = Editor {
-            grid: CharacterGrid::new(INITIAL_DIMENSIONS),
+        let default_style = Arc::new(Style::new(Colors::new(Some(colors::WHITE), Some(colors::BLACK), Some(colors::GREY))));
+        let (_, height) = INITIAL_DIMENSIONS;
+
+        let mut windows = HashMap::new();
+        windows.insert(DEFAULT_GRID, Window::new(DEFAULT_GRID, INITIAL_DIMENSIONS));
+
+        Editor {
+            windows,
+            mode: "normal".to_string(),
+            cursor_grid: DEFAULT_GRID,
             title: "Neovide".to_string(),
             font_name: None,
             font_size: None,
             cursor: Cursor::new(),
-            default_style: Arc::new(Style::new(Colors::new(Some(colors::WHITE), Some(colors::BLACK), Some(colors::GREY)))),
+            command_line: CommandLine::default(),
+            messages: Messages::default(),
+            popup_menu: PopupMenu::default(),
+            tabline: Tabline::default(),
+            default_style: default_style.clone(),
             defined_styles: HashMap::new(),
-            previous_style: None
-        };
+            highlight_groups: HashMap::new(),
+            previous_style: None,
+            window_order: 0,
+            bell: false,
+            request_attention: false,
+            frame: RenderFrame {
+                windows: Vec::new(),
+                default_style,
+                cursor: Cursor::new(),
+                cursor_grid: DEFAULT_GRID,
+                cursor_cell: CursorCell::empty(),
+                grid_height: height,
+                command_line: CommandLine::default(),
+                messages: Messages::default(),
+                popup_menu: PopupMenu::default(),
+                tabline: Tabline::default(),
+                font_name: None,
+                font_size: None,
+                bell: false,
+                request_attention: false
+            }
+        }
+    }
 
-        editor.grid.clear();
-        editor
+
+    // Whether keys typed in the current mode end up as text. Elsewhere they are commands, which an
+    // input method must not get to compose.
+    pub fn accepts_text_input(&self) -> bool {
+        match self.mode.as_ref() {
+            "insert" | "replace" | "cmdline_normal" | "cmdline_insert" | "cmdline_replace" => true,
+            _ => false
+        }
     }
 
+    // Forgets everything the last neovim instance drew. A new instance starts from an empty
+    // screen and never closes the grids, floats or overlays left over from the old one.
+    pub fn reset(&mut self) {
+        *self = Editor::new();
+    }
+
+    // The keys which yank the selection into the system clipboard, if there is one. Select mode
+    // would replace the selection with typed keys, so it switches to visual mode first.
+    pub fn copy_keys(&self) -> Option<&'static str> {
+        match self.mode.as_ref() {
+            "visual" => Some("\"+y"),
+            "visual_select" => Some("<C-g>\"+y"),
+            _ => None
+        }
+    }
 
     pub fn handle_redraw_event(&mut self, event: RedrawEvent) {
         match event {
             RedrawEvent::SetTitle { title } => self.title = title,
             RedrawEvent::ModeInfoSet { cursor_modes } => self.cursor.mode_list = cursor_modes,
             RedrawEvent::OptionSet { gui_option } => self.set_option(gui_option),
-            RedrawEvent::ModeChange { mode_index } => self.cursor.change_mode(mode_index, &self.defined_styles),
+            RedrawEvent::ModeChange { mode, mode_index } => {
+                self.mode = self.cursor.mode_list.get(mode_index as usize)
+                    .and_then(|cursor_mode| cursor_mode.name.clone())
+                    .unwrap_or(mode);
+                self.cursor.change_mode(mode_index, &self.defined_styles);
+            },
             RedrawEvent::BusyStart => {
                 trace!("Cursor off");
                 self.cursor.enabled = false;
@@ -72,97 +234,298 @@ impl Editor {
                 trace!("Cursor on");
                 self.cursor.enabled = true;
             },
+            // Neovide can't play sounds, so the audible bell is shown the same way as the visual
+            // one.
+            RedrawEvent::Bell | RedrawEvent::VisualBell => {
+                self.bell = true;
+                self.request_attention = true;
+            },
             RedrawEvent::Flush => {
                 trace!("Image flushed");
+                self.publish_frame();
                 REDRAW_SCHEDULER.queue_next_frame();
+                REDRAW_SCHEDULER.wake();
             },
-            RedrawEvent::Resize { width, height, .. } => self.grid.resize(width, height),
+            RedrawEvent::Resize { grid, width, height } => self.window_mut(grid).grid.resize(width, height),
             RedrawEvent::DefaultColorsSet { colors } => self.default_style = Arc::new(Style::new(colors)),
             RedrawEvent::HighlightAttributesDefine { id, style } => { self.defined_styles.insert(id, Arc::new(style)); },
-            RedrawEvent::GridLine { row, column_start, cells, .. } => self.draw_grid_line(row, column_start, cells),
-            RedrawEvent::Clear { .. } => self.grid.clear(),
-            RedrawEvent::CursorGoto { row, column, .. } => self.cursor.position = (row, column),
-            RedrawEvent::Scroll { top, bottom, left, right, rows, columns, .. } => self.scroll_region(top, bottom, left, right, rows, columns),
-            _ => {}
+            RedrawEvent::HighlightGroupSet { name, id } => { self.highlight_groups.insert(name, id); },
+            RedrawEvent::GridLine { grid, row, column_start, cells } => self.draw_grid_line(grid, row, column_start, cells),
+            RedrawEvent::Clear { grid } => self.window_mut(grid).grid.clear(),
+            RedrawEvent::CursorGoto { grid, row, column } => {
+                self.cursor_grid = grid;
+                self.cursor.position = (row, column);
+            },
+            RedrawEvent::Scroll { grid, top, bottom, left, right, rows, columns } => self.window_mut(grid).scroll_region(top, bottom, left, right, rows, columns),
+            RedrawEvent::WindowPosition { grid, window, start_row, start_column, .. } => 
+                self.position_window(grid, Some(window), (start_column as f64, start_row as f64), WindowKind::Editor),
+            RedrawEvent::WindowFloatPosition { grid, window, anchor, anchor_grid, anchor_row, anchor_column, .. } => {
+                let position = self.float_position(grid, anchor, anchor_grid, anchor_row, anchor_column);
+                self.position_window(grid, Some(window), position, WindowKind::Floating);
+            },
+            RedrawEvent::WindowExternalPosition { grid, window } => self.position_window(grid, Some(window), (0.0, 0.0), WindowKind::External),
+            RedrawEvent::WindowHide { grid } | RedrawEvent::WindowClose { grid } => {
+                if let Some(window) = self.windows.get_mut(&grid) {
+                    window.hidden = true;
+                }
+            },
+            RedrawEvent::GridDestroy { grid } => {
+                if grid != DEFAULT_GRID {
+                    self.windows.remove(&grid);
+                }
+            },
+            RedrawEvent::MessageSetPosition { grid, row, .. } => self.position_window(grid, None, (0.0, row as f64), WindowKind::Message),
+            RedrawEvent::CommandLineShow { content, position, first_character, prompt, indent, level } => {
+                let content = self.resolve_styled_content(content);
+                self.command_line.show(level, CommandLineLevel::new(content, position, first_character, prompt, indent));
+            },
+            RedrawEvent::CommandLinePosition { position, level } => self.command_line.set_position(position, level),
+            RedrawEvent::CommandLineSpecialCharacter { character, shift, level } => self.command_line.set_special_character(character, shift, level),
+            RedrawEvent::CommandLineHide => self.command_line.hide(),
+            RedrawEvent::CommandLineBlockShow { lines } => {
+                let lines = lines.into_iter().map(|line| self.resolve_styled_content(line)).collect();
+                self.command_line.show_block(lines);
+            },
+            RedrawEvent::CommandLineBlockAppend { line } => {
+                let line = self.resolve_styled_content(line);
+                self.command_line.append_block_line(line);
+            },
+            RedrawEvent::CommandLineBlockHide => self.command_line.hide_block(),
+            RedrawEvent::MessageShow { kind, content, replace_last } => {
+                if kind.is_error() {
+                    self.request_attention = true;
+                }
+                let content = self.resolve_styled_content(content);
+                let timeout = Duration::from_millis(SETTINGS.get("message_timeout").read_u16() as u64);
+                self.messages.show(kind, content, replace_last, timeout);
+            },
+            RedrawEvent::MessageClear => self.messages.clear(),
+            RedrawEvent::MessageShowMode { content } => self.messages.show_mode = self.resolve_styled_content(content),
+            RedrawEvent::MessageShowCommand { content } => self.messages.show_command = self.resolve_styled_content(content),
+            RedrawEvent::MessageRuler { content } => self.messages.ruler = self.resolve_styled_content(content),
+            RedrawEvent::MessageHistoryShow { entries } => {
+                let entries = entries.into_iter()
+                    .map(|(kind, content)| (kind, self.resolve_styled_content(content)))
+                    .collect();
+                self.messages.show_history(entries);
+            },
+            RedrawEvent::PopupMenuShow { items, selected, row, column, grid } => self.popup_menu.show(items, selected, (column, row), grid),
+            RedrawEvent::PopupMenuSelect { selected } => self.popup_menu.select(selected),
+            RedrawEvent::PopupMenuHide => self.popup_menu.hide(),
+            RedrawEvent::TablineUpdate { current_tab, tabs } => self.tabline.update(current_tab, tabs)
         };
     }
 
-    pub fn build_draw_commands(&mut self) -> (Vec<DrawCommand>, bool) {
-        let mut draw_commands = Vec::new();
-        for (row_index, row) in self.grid.rows().enumerate() {
-            let mut command = None;
+    // Grids are created by the first event which mentions them, which is normally a grid_resize.
+    fn window_mut(&mut self, grid: u64) -> &mut Window {
+        self.windows.entry(grid).or_insert_with(|| Window::new(grid, (0, 0)))
+    }
 
-            fn add_command(commands_list: &mut Vec<DrawCommand>, command: Option<DrawCommand>) {
-                if let Some(command) = command {
-                    commands_list.push(command);
-                }
-            }
+    fn position_window(&mut self, grid: u64, window_handle: Option<u64>, position: (f64, f64), kind: WindowKind) {
+        self.window_order += 1;
+        let order = self.window_order;
 
-            fn command_matches(command: &Option<DrawCommand>, style: &Option<Arc<Style>>) -> bool {
-                match command {
-                    Some(command) => &command.style == style,
-                    None => true
-                }
-            }
+        let window = self.window_mut(grid);
+        window.window_handle = window_handle;
+        window.position = position;
+        window.set_kind(kind);
+        // External windows lose their OS window while hidden.
+        if window.hidden && kind == WindowKind::External {
+            window.invalidate();
+        }
+        window.hidden = false;
+        window.order = order;
+    }
 
-            fn add_character(command: &mut Option<DrawCommand>, character: &str, row_index: u64, col_index: u64, style: Option<Arc<Style>>) {
-                match command {
-                    Some(command) => {
-                        command.text.push_str(character);
-                        command.cell_width += 1;
-                    },
-                    None => {
-                        command.replace(DrawCommand::new(character.to_string(), 1, (col_index, row_index), style));
-                    }
-                }
-            }
+    // Floats are placed relative to the grid they are anchored to, with the anchor picking which
+    // corner of the float lands on the anchor cell.
+    fn float_position(&self, grid: u64, anchor: WindowAnchor, anchor_grid: u64, anchor_row: f64, anchor_column: f64) -> (f64, f64) {
+        let (width, height) = self.windows.get(&grid)
+            .map(|window| (window.grid.width as f64, window.grid.height as f64))
+            .unwrap_or((0.0, 0.0));
+        let (anchor_left, anchor_top) = self.windows.get(&anchor_grid)
+            .map(|window| window.position)
+            .unwrap_or((0.0, 0.0));
 
-            for (col_index, cell) in row.iter().enumerate() {
-                if let Some((character, style)) = cell {
-                    if character.is_empty() {
-                        add_character(&mut command, &" ", row_index as u64, col_index as u64, style.clone());
-                        add_command(&mut draw_commands, command);
-                        command = None;
-                    } else {
-                        if !command_matches(&command, &style) {
-                            add_command(&mut draw_commands, command);
-                            command = None;
-                        }
-                        add_character(&mut command, &character, row_index as u64, col_index as u64, style.clone());
-                    }
-                } else {
-                    if !command_matches(&command, &None) {
-                        add_command(&mut draw_commands, command);
-                        command = None;
+        let (left, top) = match anchor {
+            WindowAnchor::NorthWest => (anchor_column, anchor_row),
+            WindowAnchor::NorthEast => (anchor_column - width, anchor_row),
+            WindowAnchor::SouthWest => (anchor_column, anchor_row - height),
+            WindowAnchor::SouthEast => (anchor_column - width, anchor_row - height)
+        };
+
+        (anchor_left + left, anchor_top + top)
+    }
+
+    // Converts a position within a grid into a position relative to the default grid.
+    fn absolute_position(&self, grid: u64, (grid_x, grid_y): (u64, u64)) -> (u64, u64) {
+        let (left, top) = self.windows.get(&grid)
+            .map(|window| window.position)
+            .unwrap_or((0.0, 0.0));
+
+        ((left + grid_x as f64).max(0.0).round() as u64, (top + grid_y as f64).max(0.0).round() as u64)
+    }
+
+    fn publish_frame(&mut self) {
+        let mut previous_windows = std::mem::take(&mut self.frame.windows);
+        for window in self.windows.values_mut() {
+            let scrolls = window.take_scrolls();
+            let (draw_commands, should_clear) = window.build_draw_commands();
+            let previous_index = previous_windows.iter().position(|frame| frame.grid_id == window.grid_id);
+            let (draw_commands, scrolls, should_clear) = match previous_index {
+                Some(index) if !should_clear => {
+                    let mut previous = previous_windows.swap_remove(index);
+                    previous.draw_commands.extend(draw_commands);
+                    for scroll in scrolls {
+                        GridScroll::push(&mut previous.scrolls, scroll);
                     }
-                    add_character(&mut command, " ", row_index as u64, col_index as u64, None);
-                }
-            }
-            add_command(&mut draw_commands, command);
+                    (previous.draw_commands, previous.scrolls, previous.should_clear)
+                },
+                // A cleared surface has nothing to animate from.
+                _ => (draw_commands, Vec::new(), true)
+            };
+
+            self.frame.windows.push(WindowFrame {
+                grid_id: window.grid_id,
+                window_handle: window.window_handle,
+                kind: window.kind,
+                position: window.position,
+                size: (window.grid.width, window.grid.height),
+                hidden: window.hidden,
+                order: window.order,
+                draw_commands,
+                scrolls,
+                should_clear
+            });
         }
-        let should_clear = self.grid.should_clear;
-        
-        let draw_commands = draw_commands.into_iter().filter(|command| {
-            let (x, y) = command.grid_position;
-
-            let min = (x as i64 - 1).max(0) as u64;
-            let max = (x + command.cell_width + 1).min(self.grid.width);
-            for char_index in min..max {
-                if self.grid.is_dirty_cell(char_index, y) {
-                    return true;
-                }
+        self.frame.windows.sort_by_key(|frame| (frame.kind, frame.order));
+
+        self.frame.default_style = self.default_style.clone();
+        self.frame.cursor = self.cursor.clone();
+        self.frame.cursor.position = self.absolute_position(self.cursor_grid, self.cursor.position);
+        self.frame.cursor_grid = self.cursor_grid;
+        self.frame.cursor_cell = self.cursor_cell();
+        self.frame.grid_height = self.windows.get(&DEFAULT_GRID).map(|window| window.grid.height).unwrap_or(0);
+        self.frame.command_line = self.command_line.clone();
+        self.frame.messages = self.messages.clone();
+        self.frame.messages.styles = self.message_styles();
+        self.frame.popup_menu = self.popup_menu.clone();
+        self.frame.popup_menu.styles = self.popup_menu_styles();
+        if !self.popup_menu.is_command_line_menu() {
+            self.frame.popup_menu.anchor = self.absolute_position(self.popup_menu.grid as u64, self.popup_menu.anchor);
+        }
+        self.frame.tabline = self.tabline.clone();
+        self.frame.tabline.styles = self.tabline_styles();
+        self.frame.font_name = self.font_name.clone();
+        self.frame.font_size = self.font_size;
+        self.frame.bell |= std::mem::replace(&mut self.bell, false);
+        self.frame.request_attention |= std::mem::replace(&mut self.request_attention, false);
+    }
+
+    // Takes the draw commands published since the last call along with a copy of the rest of the
+    // published state.
+    pub fn take_render_frame(&mut self) -> RenderFrame {
+        RenderFrame {
+            windows: self.frame.windows.iter_mut().map(WindowFrame::take).collect(),
+            default_style: self.frame.default_style.clone(),
+            cursor: self.frame.cursor.clone(),
+            cursor_grid: self.frame.cursor_grid,
+            cursor_cell: self.frame.cursor_cell.clone(),
+            grid_height: self.frame.grid_height,
+            command_line: self.frame.command_line.clone(),
+            messages: self.frame.messages.clone(),
+            popup_menu: self.frame.popup_menu.clone(),
+            tabline: self.frame.tabline.clone(),
+            font_name: self.frame.font_name.clone(),
+            font_size: self.frame.font_size,
+            bell: std::mem::replace(&mut self.frame.bell, false),
+            request_attention: std::mem::replace(&mut self.frame.request_attention, false)
+        }
+    }
+
+    fn resolve_styled_content(&self, content: StyledContent) -> StyledText {
+        content.into_iter()
+            .map(|(style_id, text)| (self.defined_styles.get(&style_id).cloned(), text))
+            .collect()
+    }
+
+    fn highlight_group_style(&self, name: &str) -> Option<Arc<Style>> {
+        self.highlight_groups.get(name)
+            .and_then(|id| self.defined_styles.get(id))
+            .cloned()
+    }
+
+    fn popup_menu_styles(&self) -> PopupMenuStyles {
+        PopupMenuStyles {
+            normal: self.highlight_group_style("Pmenu"),
+            selected: self.highlight_group_style("PmenuSel"),
+            scrollbar: self.highlight_group_style("PmenuSbar"),
+            thumb: self.highlight_group_style("PmenuThumb")
+        }
+    }
+
+    fn message_styles(&self) -> MessageStyles {
+        MessageStyles {
+            error: self.highlight_group_style("ErrorMsg"),
+            warning: self.highlight_group_style("WarningMsg"),
+            search: self.highlight_group_style("Search")
+        }
+    }
+
+    fn tabline_styles(&self) -> TablineStyles {
+        TablineStyles {
+            fill: self.highlight_group_style("TabLineFill"),
+            tab: self.highlight_group_style("TabLine"),
+            selected: self.highlight_group_style("TabLineSel")
+        }
+    }
+
+    fn cursor_cell(&self) -> CursorCell {
+        let (grid_x, grid_y) = self.cursor.position;
+        let grid = match self.windows.get(&self.cursor_grid) {
+            Some(window) => &window.grid,
+            None => return CursorCell::empty()
+        };
+
+        let (character, style) = match grid.get_cell(grid_x, grid_y) {
+            Some(Some((character, style))) => (character.clone(), style.clone()),
+            _ => (' '.to_string(), None),
+        };
+
+        let double_width = match grid.get_cell(grid_x + 1, grid_y) {
+            Some(Some((character, _))) => character.is_empty(),
+            _ => false,
+        };
+
+        // Ligatures never span whitespace or a change in style. The empty cells following
+        // double width characters are part of the character before them.
+        let in_run = |x: u64| match grid.get_cell(x, grid_y) {
+            Some(Some((character, cell_style))) => (cell_style == &style && !character.trim().is_empty()) || character.is_empty(),
+            _ => false
+        };
+
+        let (mut run_start, mut run_end) = (grid_x, grid_x + 1);
+        if in_run(grid_x) {
+            while run_start > 0 && in_run(run_start - 1) {
+                run_start -= 1;
+            }
+            while in_run(run_end) {
+                run_end += 1;
             }
-            false
-        }).collect::<Vec<DrawCommand>>();
+        }
 
-        self.grid.set_dirty_all(false);
-        self.grid.should_clear = false;
+        let ligature_run = (run_start..run_end)
+            .filter_map(|x| match grid.get_cell(x, grid_y) {
+                Some(Some((character, _))) => Some(character.as_str()),
+                _ => None
+            })
+            .collect::<String>();
+        let ligature_run = if ligature_run.is_empty() { character.clone() } else { ligature_run };
 
-        trace!("Draw commands sent");
-        (draw_commands, should_clear)
+        CursorCell::new(character, double_width, style, ligature_run, grid_x - run_start)
     }
 
-    fn draw_grid_line_cell(&mut self, row_index: u64, column_pos: &mut u64, cell: GridLineCell) {
+    fn draw_grid_line_cell(&mut self, grid_id: u64, row_index: u64, column_pos: &mut u64, cell: GridLineCell) {
         let style = match cell.highlight_id {
             Some(0) => None,
             Some(style_id) => self.defined_styles.get(&style_id).cloned(),
@@ -174,18 +537,19 @@ impl Editor {
             text = text.repeat(times as usize);
         }
 
+        let grid = &mut self.window_mut(grid_id).grid;
         if text.is_empty() {
-            if let Some(cell) = self.grid.get_cell_mut(*column_pos, row_index) {
+            if let Some(cell) = grid.get_cell_mut(*column_pos, row_index) {
                 *cell = Some(("".to_string(), style.clone()));
             }
 
-            self.grid.set_dirty_cell(*column_pos, row_index);
+            grid.set_dirty_cell(*column_pos, row_index);
             *column_pos += 1;
         } else {
             for (i, character) in text.graphemes(true).enumerate() {
-                if let Some(cell) = self.grid.get_cell_mut(i as u64 + *column_pos, row_index) {
+                if let Some(cell) = grid.get_cell_mut(i as u64 + *column_pos, row_index) {
                     *cell = Some((character.to_string(), style.clone()));
-                    self.grid.set_dirty_cell(*column_pos, row_index);
+                    grid.set_dirty_cell(*column_pos, row_index);
                 }
             }
             *column_pos += text.graphemes(true).count() as u64;
@@ -193,50 +557,17 @@ impl Editor {
         self.previous_style = style;
     }
 
-    fn draw_grid_line(&mut self, row: u64, column_start: u64, cells: Vec<GridLineCell>) {
-        if row < self.grid.height {
+    fn draw_grid_line(&mut self, grid: u64, row: u64, column_start: u64, cells: Vec<GridLineCell>) {
+        if row < self.window_mut(grid).grid.height {
             let mut column_pos = column_start;
             for cell in cells {
-                self.draw_grid_line_cell(row, &mut column_pos, cell);
+                self.draw_grid_line_cell(grid, row, &mut column_pos, cell);
             }
         } else {
             println!("Draw command out of bounds");
         }
     }
 
-    fn scroll_region(&mut self, top: u64, bot: u64, left: u64, right: u64, rows: i64, cols: i64) {
-        let y_iter : Box<dyn Iterator<Item=i64>> = if rows > 0 {
-            Box::new((top as i64 + rows).. bot as i64)
-        } else {
-            Box::new((top as i64 .. (bot as i64 + rows)).rev())
-        };
-
-        for y in y_iter {
-            let dest_y = y - rows;
-            if dest_y >= 0 && dest_y < self.grid.height as i64 {
-
-                let x_iter : Box<dyn Iterator<Item=i64>> = if cols > 0 {
-                    Box::new((left as i64 + cols) .. right as i64)
-                } else {
-                    Box::new((left as i64 .. (right as i64 + cols)).rev())
-                };
-
-                for x in x_iter {
-                    let dest_x = x - cols;
-                    let cell_data = self.grid.get_cell(x as u64, y as u64).cloned();
-
-                    if let Some(cell_data) =  cell_data {
-                        if let Some(dest_cell) = self.grid.get_cell_mut(dest_x as u64, dest_y as u64) {
-                            *dest_cell = cell_data;
-                            self.grid.set_dirty_cell(dest_x as u64, dest_y as u64);
-                        }
-                    }
-                }
-            }
-        }
-        trace!("Region scrolled");
-    }
-
     fn set_option(&mut self, gui_option: GuiOption) {
         trace!("Option set {:?}", &gui_option);
         match gui_option {
@@ -249,8 +580,38 @@ impl Editor {
                     }
                 }
             },
+            GuiOption::Pumblend(blend) => self.popup_menu.blend = blend,
+            GuiOption::ShowTabLine(show_tabline) => self.tabline.show_tabline = show_tabline,
             _ => {}
         }
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn editor_in_mode(mode: &str) -> Editor {
+        let mut editor = Editor::new();
+        editor.mode = mode.to_string();
+        editor
+    }
+
+    #[test]
+    fn copy_yanks_visual_selections() {
+        assert_eq!(editor_in_mode("visual").copy_keys(), Some("\"+y"));
+    }
+
+    #[test]
+    fn copy_leaves_select_mode_before_yanking() {
+        assert_eq!(editor_in_mode("visual_select").copy_keys(), Some("<C-g>\"+y"));
+    }
+
+    #[test]
+    fn copy_is_left_to_neovim_without_a_selection() {
+        for mode in &["normal", "insert", "replace", "cmdline_normal", "operator"] {
+            assert_eq!(editor_in_mode(mode).copy_keys(), None);
+        }
+    }
+}
@@ -0,0 +1,49 @@
+use std::sync::Arc;
+
+use crate::bridge::PopupMenuItem;
+use super::Style;
+
+// Highlights for the parts of the menu, looked up through hl_group_set since the items
+// themselves don't carry any.
+#[derive(Debug, Clone, Default)]
+pub struct PopupMenuStyles {
+    pub normal: Option<Arc<Style>>,
+    pub selected: Option<Arc<Style>>,
+    pub scrollbar: Option<Arc<Style>>,
+    pub thumb: Option<Arc<Style>>
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct PopupMenu {
+    pub items: Vec<PopupMenuItem>,
+    pub selected: Option<u64>,
+    pub anchor: (u64, u64),
+    pub grid: i64,
+    pub visible: bool,
+    pub blend: u64,
+    pub styles: PopupMenuStyles
+}
+
+impl PopupMenu {
+    pub fn show(&mut self, items: Vec<PopupMenuItem>, selected: Option<u64>, anchor: (u64, u64), grid: i64) {
+        self.items = items;
+        self.selected = selected;
+        self.anchor = anchor;
+        self.grid = grid;
+        self.visible = true;
+    }
+
+    pub fn select(&mut self, selected: Option<u64>) {
+        self.selected = selected;
+    }
+
+    pub fn hide(&mut self) {
+        self.items.clear();
+        self.selected = None;
+        self.visible = false;
+    }
+
+    pub fn is_command_line_menu(&self) -> bool {
+        self.grid < 0
+    }
+}
@@ -46,4 +46,10 @@ impl Style {
     pub fn special(&self, default_colors: &Colors) -> Color4f {
         self.colors.special.clone().unwrap_or_else(||default_colors.special.clone().unwrap())
     }
+
+    // Blend is the percentage of whatever sits underneath which should show through the background,
+    // as set by winblend and pumblend.
+    pub fn background_alpha(&self) -> u8 {
+        (255 * (100 - self.blend.min(100) as u32) / 100) as u8
+    }
 }
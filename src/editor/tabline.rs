@@ -0,0 +1,53 @@
+use std::sync::Arc;
+
+use rmpv::Value;
+
+use crate::bridge::TabInfo;
+use super::Style;
+
+#[derive(Debug, Clone, Default)]
+pub struct TablineStyles {
+    pub fill: Option<Arc<Style>>,
+    pub tab: Option<Arc<Style>>,
+    pub selected: Option<Arc<Style>>
+}
+
+#[derive(Debug, Clone)]
+pub struct Tabline {
+    pub tabs: Vec<TabInfo>,
+    pub current_tab: Value,
+    // Mirrors the showtabline option: 0 never shows the tabline, 1 only shows it when there is
+    // more than one tab and 2 always shows it.
+    pub show_tabline: u64,
+    pub styles: TablineStyles
+}
+
+impl Default for Tabline {
+    fn default() -> Tabline {
+        Tabline {
+            tabs: Vec::new(),
+            current_tab: Value::Nil,
+            show_tabline: 1,
+            styles: TablineStyles::default()
+        }
+    }
+}
+
+impl Tabline {
+    pub fn update(&mut self, current_tab: Value, tabs: Vec<TabInfo>) {
+        self.current_tab = current_tab;
+        self.tabs = tabs;
+    }
+
+    pub fn is_visible(&self) -> bool {
+        match self.show_tabline {
+            0 => false,
+            1 => self.tabs.len() > 1,
+            _ => !self.tabs.is_empty()
+        }
+    }
+
+    pub fn is_current(&self, tab: &TabInfo) -> bool {
+        tab.tab == self.current_tab
+    }
+}
@@ -0,0 +1,203 @@
+use std::sync::Arc;
+
+use log::trace;
+
+use super::{CharacterGrid, DrawCommand, Style};
+
+// Windows are composited in this order, so floating windows always sit above regular ones and the
+// message area sits above both. External windows get an OS window of their own instead of being
+// drawn into the main one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum WindowKind {
+    Editor,
+    Floating,
+    Message,
+    External
+}
+
+// A region of a grid which neovim scrolled, kept around so that the renderer can animate the
+// move. Neovim only ever scrolls by whole rows.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GridScroll {
+    pub top: u64,
+    pub bottom: u64,
+    pub left: u64,
+    pub right: u64,
+    pub rows: i64
+}
+
+impl GridScroll {
+    // Scrolls of the same region in a row are folded into one, since the renderer only sees the
+    // grid as it was before all of them.
+    pub fn push(scrolls: &mut Vec<GridScroll>, scroll: GridScroll) {
+        if let Some(previous) = scrolls.last_mut() {
+            if (previous.top, previous.bottom, previous.left, previous.right) == (scroll.top, scroll.bottom, scroll.left, scroll.right) {
+                previous.rows += scroll.rows;
+                return;
+            }
+        }
+        scrolls.push(scroll);
+    }
+}
+
+// A single neovim grid along with where it belongs on screen. Without ext_multigrid there is only
+// ever the default grid, which fills the whole window.
+pub struct Window {
+    pub grid_id: u64,
+    pub window_handle: Option<u64>,
+    pub grid: CharacterGrid,
+    pub kind: WindowKind,
+    // Position of the top left corner in cells relative to the default grid. Floating windows can
+    // be anchored at fractional cells.
+    pub position: (f64, f64),
+    pub hidden: bool,
+    // Windows of the same kind are stacked in the order they were last positioned in.
+    pub order: u64,
+    pub scrolls: Vec<GridScroll>
+}
+
+impl Window {
+    pub fn new(grid_id: u64, size: (u64, u64)) -> Window {
+        Window {
+            grid_id,
+            window_handle: None,
+            grid: CharacterGrid::new(size),
+            kind: WindowKind::Editor,
+            position: (0.0, 0.0),
+            hidden: false,
+            order: 0,
+            scrolls: Vec::new()
+        }
+    }
+
+    // Sends the whole grid again on the next flush, for when whatever it was drawn into before
+    // is gone.
+    pub fn invalidate(&mut self) {
+        self.grid.set_dirty_all(true);
+        self.grid.should_clear = true;
+        self.scrolls.clear();
+    }
+
+    pub fn set_kind(&mut self, kind: WindowKind) {
+        if self.kind != kind {
+            self.invalidate();
+        }
+        self.kind = kind;
+    }
+
+    pub fn take_scrolls(&mut self) -> Vec<GridScroll> {
+        std::mem::take(&mut self.scrolls)
+    }
+
+    pub fn build_draw_commands(&mut self) -> (Vec<DrawCommand>, bool) {
+        let mut draw_commands = Vec::new();
+        for (row_index, row) in self.grid.rows().enumerate() {
+            let mut command = None;
+
+            fn add_command(commands_list: &mut Vec<DrawCommand>, command: Option<DrawCommand>) {
+                if let Some(command) = command {
+                    commands_list.push(command);
+                }
+            }
+
+            fn command_matches(command: &Option<DrawCommand>, style: &Option<Arc<Style>>) -> bool {
+                match command {
+                    Some(command) => &command.style == style,
+                    None => true
+                }
+            }
+
+            fn add_character(command: &mut Option<DrawCommand>, character: &str, row_index: u64, col_index: u64, style: Option<Arc<Style>>) {
+                match command {
+                    Some(command) => {
+                        command.text.push_str(character);
+                        command.cell_width += 1;
+                    },
+                    None => {
+                        command.replace(DrawCommand::new(character.to_string(), 1, (col_index, row_index), style));
+                    }
+                }
+            }
+
+            for (col_index, cell) in row.iter().enumerate() {
+                if let Some((character, style)) = cell {
+                    if character.is_empty() {
+                        add_character(&mut command, &" ", row_index as u64, col_index as u64, style.clone());
+                        add_command(&mut draw_commands, command);
+                        command = None;
+                    } else {
+                        if !command_matches(&command, &style) {
+                            add_command(&mut draw_commands, command);
+                            command = None;
+                        }
+                        add_character(&mut command, &character, row_index as u64, col_index as u64, style.clone());
+                    }
+                } else {
+                    if !command_matches(&command, &None) {
+                        add_command(&mut draw_commands, command);
+                        command = None;
+                    }
+                    add_character(&mut command, " ", row_index as u64, col_index as u64, None);
+                }
+            }
+            add_command(&mut draw_commands, command);
+        }
+        let should_clear = self.grid.should_clear;
+
+        let grid = &self.grid;
+        let draw_commands = draw_commands.into_iter().filter(|command| {
+            let (x, y) = command.grid_position;
+
+            let min = (x as i64 - 1).max(0) as u64;
+            let max = (x + command.cell_width + 1).min(grid.width);
+            for char_index in min..max {
+                if grid.is_dirty_cell(char_index, y) {
+                    return true;
+                }
+            }
+            false
+        }).collect::<Vec<DrawCommand>>();
+
+        self.grid.set_dirty_all(false);
+        self.grid.should_clear = false;
+
+        trace!("Draw commands sent");
+        (draw_commands, should_clear)
+    }
+
+    pub fn scroll_region(&mut self, top: u64, bot: u64, left: u64, right: u64, rows: i64, cols: i64) {
+        let y_iter : Box<dyn Iterator<Item=i64>> = if rows > 0 {
+            Box::new((top as i64 + rows).. bot as i64)
+        } else {
+            Box::new((top as i64 .. (bot as i64 + rows)).rev())
+        };
+
+        for y in y_iter {
+            let dest_y = y - rows;
+            if dest_y >= 0 && dest_y < self.grid.height as i64 {
+
+                let x_iter : Box<dyn Iterator<Item=i64>> = if cols > 0 {
+                    Box::new((left as i64 + cols) .. right as i64)
+                } else {
+                    Box::new((left as i64 .. (right as i64 + cols)).rev())
+                };
+
+                for x in x_iter {
+                    let dest_x = x - cols;
+                    let cell_data = self.grid.get_cell(x as u64, y as u64).cloned();
+
+                    if let Some(cell_data) =  cell_data {
+                        if let Some(dest_cell) = self.grid.get_cell_mut(dest_x as u64, dest_y as u64) {
+                            *dest_cell = cell_data;
+                            self.grid.set_dirty_cell(dest_x as u64, dest_y as u64);
+                        }
+                    }
+                }
+            }
+        }
+        if cols == 0 {
+            GridScroll::push(&mut self.scrolls, GridScroll { top, bottom: bot, left, right, rows });
+        }
+        trace!("Region scrolled");
+    }
+}
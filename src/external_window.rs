@@ -0,0 +1,167 @@
+use log::{info, error};
+use skulpin::{LogicalSize, PhysicalSize, RendererBuilder, PresentMode, CoordinateSystem};
+use skulpin::Renderer as SkulpinRenderer;
+use skulpin::sdl2;
+use skulpin::sdl2::event::{Event, WindowEvent};
+
+use crate::bridge::{BRIDGE, UiCommand};
+use crate::editor::{RenderFrame, WindowFrame};
+use crate::renderer::Renderer;
+use crate::window::logical_mouse_position;
+
+// A neovim window which was moved out of the main window with ext_multigrid. It gets an OS window
+// of its own so that it can be placed anywhere, including on another monitor. Keyboard input is
+// handled by the main loop since neovim sends it to whichever window is current anyway.
+pub struct ExternalWindow {
+    pub grid_id: u64,
+    window_handle: Option<u64>,
+    // Fields are dropped in order, and the surfaces have to go before the gpu context they were
+    // created in, which has to go before the window it draws to.
+    renderer: Renderer,
+    skulpin_renderer: SkulpinRenderer,
+    window: sdl2::video::Window,
+
+    focused: bool,
+    mouse_down: bool,
+    mouse_position: (u32, u32),
+    grid_size: (u64, u64)
+}
+
+impl ExternalWindow {
+    pub fn new(video_subsystem: &sdl2::VideoSubsystem, window_frame: &WindowFrame, font_width: f32, font_height: f32) -> Option<ExternalWindow> {
+        let (width, height) = window_frame.size;
+        let window = video_subsystem.window("Neovide", (width as f32 * font_width) as u32, (height as f32 * font_height) as u32)
+            .allow_highdpi()
+            .resizable()
+            .vulkan()
+            .build()
+            .map_err(|error| error!("Could not create external window: {}", error))
+            .ok()?;
+
+        let skulpin_renderer = RendererBuilder::new()
+            .prefer_integrated_gpu()
+            .present_mode_priority(vec![PresentMode::Immediate])
+            .coordinate_system(CoordinateSystem::Logical)
+            .build(&window)
+            .map_err(|error| error!("Could not create renderer for external window: {:?}", error))
+            .ok()?;
+        info!("external window created for grid {}", window_frame.grid_id);
+
+        Some(ExternalWindow {
+            grid_id: window_frame.grid_id,
+            window_handle: window_frame.window_handle,
+            renderer: Renderer::new(),
+            skulpin_renderer,
+            window,
+            focused: false,
+            mouse_down: false,
+            mouse_position: (0, 0),
+            grid_size: window_frame.size
+        })
+    }
+
+    pub fn is_focused(&self) -> bool {
+        self.focused
+    }
+
+    pub fn set_font_scale(&mut self, font_scale: f32) {
+        self.renderer.set_font_scale(font_scale);
+    }
+
+    pub fn window_id(&self) -> u32 {
+        self.window.id()
+    }
+
+    fn grid_position(&self, x: i32, y: i32) -> (u32, u32) {
+        let position = logical_mouse_position(x, y, &self.window);
+        self.renderer.grid_position(self.grid_id, position)
+    }
+
+    // Handles the mouse and window events sent to this window. Returns false for events which
+    // should be handled by the main loop instead.
+    pub fn handle_event(&mut self, event: &Event) -> bool {
+        let grid_id = self.grid_id;
+        match event {
+            Event::Window { win_event: WindowEvent::Close, .. } => {
+                // Neovim closes the window and lets us know through win_close, at which point the
+                // OS window is dropped.
+                if let Some(window_handle) = self.window_handle {
+                    BRIDGE.queue_command(UiCommand::CloseWindow(window_handle));
+                }
+            },
+            Event::Window { win_event: WindowEvent::SizeChanged(..), .. } |
+            Event::Window { win_event: WindowEvent::Resized(..), .. } => {
+                if let Ok(new_size) = LogicalSize::new(&self.window) {
+                    let width = ((new_size.width + 1) as f32 / self.renderer.font_width) as u32;
+                    let height = ((new_size.height + 1) as f32 / self.renderer.font_height) as u32;
+                    if (width as u64, height as u64) != self.grid_size && width > 0 && height > 0 {
+                        BRIDGE.queue_command(UiCommand::ResizeGrid { grid_id, width, height });
+                    }
+                }
+            },
+            Event::Window { win_event: WindowEvent::FocusGained, .. } => self.focused = true,
+            Event::Window { win_event: WindowEvent::FocusLost, .. } => self.focused = false,
+            Event::Window { .. } => {},
+            Event::MouseMotion { x, y, .. } => {
+                let position = self.grid_position(*x, *y);
+                if self.mouse_down && self.mouse_position != position {
+                    BRIDGE.queue_command(UiCommand::Drag { grid_id, position });
+                }
+                self.mouse_position = position;
+            },
+            Event::MouseButtonDown { x, y, .. } => {
+                let position = self.grid_position(*x, *y);
+                self.mouse_position = position;
+                BRIDGE.queue_command(UiCommand::MouseButton { action: String::from("press"), grid_id, position });
+                self.mouse_down = true;
+            },
+            Event::MouseButtonUp { .. } => {
+                if self.mouse_down {
+                    BRIDGE.queue_command(UiCommand::MouseButton { action: String::from("release"), grid_id, position: self.mouse_position });
+                    self.mouse_down = false;
+                }
+            },
+            Event::MouseWheel { x, y, .. } => {
+                let position = self.mouse_position;
+                if *y != 0 {
+                    let direction = if *y > 0 { "up" } else { "down" };
+                    BRIDGE.queue_command(UiCommand::Scroll { direction: direction.to_string(), grid_id, position });
+                }
+                if *x != 0 {
+                    let direction = if *x > 0 { "right" } else { "left" };
+                    BRIDGE.queue_command(UiCommand::Scroll { direction: direction.to_string(), grid_id, position });
+                }
+            },
+            _ => return false
+        }
+        true
+    }
+
+    // Draws the window's grid, following size changes neovim made to it. Returns false if
+    // rendering failed.
+    pub fn draw(&mut self, frame: &RenderFrame) -> bool {
+        if let Some(window_frame) = frame.windows.iter().find(|window| window.grid_id == self.grid_id) {
+            self.window_handle = window_frame.window_handle;
+            if window_frame.size != self.grid_size {
+                let (width, height) = window_frame.size;
+                self.grid_size = window_frame.size;
+                // The window is sized in physical pixels, so the logical size of the grid has to
+                // be scaled by the current dpi.
+                if let Ok(logical_size) = LogicalSize::new(&self.window) {
+                    let physical_size = PhysicalSize::new(&self.window);
+                    let scale = physical_size.width as f32 / logical_size.width.max(1) as f32;
+                    self.window.set_size(
+                        (width as f32 * self.renderer.font_width * scale) as u32,
+                        (height as f32 * self.renderer.font_height * scale) as u32).ok();
+                }
+            }
+        }
+
+        let grid_id = self.grid_id;
+        let focused = self.focused;
+        let renderer = &mut self.renderer;
+        self.skulpin_renderer.draw(&self.window, |canvas, coordinate_system_helper| {
+            renderer.draw_external_window(frame, grid_id, focused, canvas, coordinate_system_helper);
+        }).is_ok()
+    }
+}
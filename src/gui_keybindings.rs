@@ -0,0 +1,117 @@
+use std::collections::HashMap;
+
+use crate::settings::SETTINGS;
+
+// Things Neovide does itself rather than sending the key that triggers them to neovim.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GuiAction {
+    ZoomIn,
+    ZoomOut,
+    ZoomReset,
+    ToggleFullscreen,
+    Paste,
+    Copy,
+    NewWindow
+}
+
+impl GuiAction {
+    fn from_name(name: &str) -> Option<GuiAction> {
+        match name {
+            "zoom_in" => Some(GuiAction::ZoomIn),
+            "zoom_out" => Some(GuiAction::ZoomOut),
+            "zoom_reset" => Some(GuiAction::ZoomReset),
+            "toggle_fullscreen" => Some(GuiAction::ToggleFullscreen),
+            "paste" => Some(GuiAction::Paste),
+            "copy" => Some(GuiAction::Copy),
+            "new_window" => Some(GuiAction::NewWindow),
+            _ => None
+        }
+    }
+
+    // Finds the action bound to a key in g:neovide_keybindings. Keys are written in neovim's
+    // key notation, and a binding written exactly like the key wins over ones which only differ
+    // in case. Keys not in the table paste if they match g:neovide_paste_shortcut.
+    pub fn bound_to(key: &str) -> Option<GuiAction> {
+        let keybindings = SETTINGS.get("keybindings").read_map();
+        let paste_shortcut = SETTINGS.get("paste_shortcut").read_string();
+        GuiAction::action_for(key, &keybindings, &paste_shortcut)
+    }
+
+    fn action_for(key: &str, keybindings: &HashMap<String, String>, paste_shortcut: &str) -> Option<GuiAction> {
+        let binding = keybindings.get(key)
+            .or_else(|| keybindings.iter()
+                .find(|(binding, _)| binding.eq_ignore_ascii_case(key))
+                .map(|(_, action)| action));
+
+        match binding {
+            Some(action) => GuiAction::from_name(action),
+            None => {
+                if !paste_shortcut.is_empty() && paste_shortcut.eq_ignore_ascii_case(key) {
+                    Some(GuiAction::Paste)
+                } else {
+                    None
+                }
+            }
+        }
+    }
+}
+
+// Bindings used unless g:neovide_keybindings overrides them. Binding a key to an empty string
+// or to anything which isn't an action sends it to neovim again.
+pub fn default_keybindings() -> HashMap<String, String> {
+    let clipboard_modifier = if cfg!(target_os = "macos") { "D" } else { "C-S" };
+
+    let mut keybindings = HashMap::new();
+    keybindings.insert("<C-=>".to_string(), "zoom_in".to_string());
+    keybindings.insert("<C-->".to_string(), "zoom_out".to_string());
+    keybindings.insert("<C-0>".to_string(), "zoom_reset".to_string());
+    keybindings.insert(format!("<{}-v>", clipboard_modifier), "paste".to_string());
+    keybindings.insert(format!("<{}-c>", clipboard_modifier), "copy".to_string());
+    keybindings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keybindings(entries: &[(&str, &str)]) -> HashMap<String, String> {
+        entries.iter().map(|(key, action)| (key.to_string(), action.to_string())).collect()
+    }
+
+    #[test]
+    fn exact_bindings_win_over_ones_differing_in_case() {
+        let keybindings = keybindings(&[("<C-S-v>", "paste"), ("<C-S-V>", "copy")]);
+        assert_eq!(GuiAction::action_for("<C-S-V>", &keybindings, ""), Some(GuiAction::Copy));
+        assert_eq!(GuiAction::action_for("<C-S-v>", &keybindings, ""), Some(GuiAction::Paste));
+    }
+
+    #[test]
+    fn bindings_fall_back_to_ones_differing_in_case() {
+        let keybindings = keybindings(&[("<c-=>", "zoom_in")]);
+        assert_eq!(GuiAction::action_for("<C-=>", &keybindings, ""), Some(GuiAction::ZoomIn));
+        assert_eq!(GuiAction::action_for("<C-->", &keybindings, ""), None);
+    }
+
+    #[test]
+    fn empty_bindings_unbind_keys() {
+        let mut keybindings = default_keybindings();
+        keybindings.insert("<C-=>".to_string(), String::new());
+        assert_eq!(GuiAction::action_for("<C-=>", &keybindings, ""), None);
+        assert_eq!(GuiAction::action_for("<C-->", &keybindings, ""), Some(GuiAction::ZoomOut));
+    }
+
+    #[test]
+    fn unknown_actions_unbind_keys() {
+        let keybindings = keybindings(&[("<C-=>", "explode")]);
+        assert_eq!(GuiAction::action_for("<C-=>", &keybindings, ""), None);
+    }
+
+    #[test]
+    fn paste_shortcut_only_applies_to_unbound_keys() {
+        let keybindings = keybindings(&[("<C-v>", "zoom_in"), ("<S-Insert>", "")]);
+        assert_eq!(GuiAction::action_for("<C-v>", &keybindings, "<C-v>"), Some(GuiAction::ZoomIn));
+        assert_eq!(GuiAction::action_for("<S-Insert>", &keybindings, "<S-Insert>"), None);
+        assert_eq!(GuiAction::action_for("<M-v>", &keybindings, "<m-V>"), Some(GuiAction::Paste));
+        assert_eq!(GuiAction::action_for("<M-v>", &keybindings, ""), None);
+    }
+}
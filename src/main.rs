@@ -3,6 +3,9 @@
 mod bridge;
 mod editor;
 mod window;
+mod attention;
+mod external_window;
+mod gui_keybindings;
 mod renderer;
 mod error_handling;
 mod redraw_scheduler;
@@ -1,6 +1,6 @@
-use std::sync::Mutex;
+use std::sync::{Mutex, Condvar};
 use std::sync::atomic::{AtomicU16, Ordering};
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 use log::trace;
 
@@ -12,14 +12,18 @@ lazy_static! {
 
 pub struct RedrawScheduler {
     frames_queued: AtomicU16,
-    scheduled_frame: Mutex<Option<Instant>>
+    scheduled_frame: Mutex<Option<Instant>>,
+    wake_requested: Mutex<bool>,
+    wake_condvar: Condvar
 }
 
 impl RedrawScheduler {
     pub fn new() -> RedrawScheduler {
         RedrawScheduler { 
             frames_queued: AtomicU16::new(1),
-            scheduled_frame: Mutex::new(None)
+            scheduled_frame: Mutex::new(None),
+            wake_requested: Mutex::new(false),
+            wake_condvar: Condvar::new()
         }
     }
 
@@ -41,6 +45,23 @@ impl RedrawScheduler {
         self.frames_queued.store(buffer_frames, Ordering::Relaxed);
     }
 
+    // Interrupts the ui loop's sleep so that a freshly flushed frame is presented right away
+    // instead of on the next tick.
+    pub fn wake(&self) {
+        trace!("Ui loop woken");
+        let mut wake_requested = self.wake_requested.lock().unwrap();
+        *wake_requested = true;
+        self.wake_condvar.notify_one();
+    }
+
+    pub fn sleep(&self, duration: Duration) {
+        let wake_requested = self.wake_requested.lock().unwrap();
+        let (mut wake_requested, _) = self.wake_condvar
+            .wait_timeout_while(wake_requested, duration, |wake_requested| !*wake_requested)
+            .unwrap();
+        *wake_requested = false;
+    }
+
     pub fn should_draw(&self) -> bool {
         let frames_queued = self.frames_queued.load(Ordering::Relaxed);
         if frames_queued > 0 {
@@ -0,0 +1,5 @@
+// Fast at first and slow to settle, so motion responds right away but doesn't stop abruptly.
+pub fn ease_out_cubic(t: f32) -> f32 {
+    let remaining = 1.0 - t.min(1.0).max(0.0);
+    1.0 - remaining * remaining * remaining
+}
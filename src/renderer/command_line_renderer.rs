@@ -0,0 +1,86 @@
+use std::sync::Arc;
+
+use skulpin::skia_safe::{Canvas, Paint, Point, Rect, Color};
+
+use crate::editor::{CommandLine, Style, StyledText};
+use super::CachingShaper;
+use super::overlay::{draw_bordered_panel, draw_styled_text, styled_text_width};
+
+const MINIMUM_WIDTH_PERCENTAGE: f32 = 0.6;
+const TOP_PERCENTAGE: f32 = 0.2;
+const PADDING: f32 = 1.0;
+const LEVEL_SPACING: f32 = 0.5;
+const CURSOR_WIDTH_PERCENTAGE: f32 = 1.0 / 8.0;
+
+// Draws the external command line as a floating box centered near the top of the window. Nested
+// levels (for instance <C-r>= inside of a command) stack below their parent. Returns where the text
+// of the innermost level starts so that things anchored to the command line can line up with it.
+pub fn draw_command_line(
+        command_line: &CommandLine, default_style: &Arc<Style>,
+        canvas: &mut Canvas, paint: &mut Paint, shaper: &mut CachingShaper,
+        font_width: f32, font_height: f32,
+        window_width: f32, window_height: f32) -> Option<Point> {
+    let background = default_style.colors.background.clone().unwrap().to_color();
+    let foreground = default_style.colors.foreground.clone().unwrap().to_color();
+    let border = Color::from_argb(96, foreground.r(), foreground.g(), foreground.b());
+
+    let mut top = window_height * TOP_PERCENTAGE;
+    let mut text_origin = None;
+    for (level_index, level) in command_line.levels.iter().enumerate() {
+        let mut current_line: StyledText = vec![(None, level.prefix())];
+        current_line.extend(level.content.iter().cloned());
+
+        // Multiline blocks such as :function bodies belong to the outermost command line.
+        let block: &[StyledText] = if level_index == 0 {
+            &command_line.block
+        } else {
+            &[]
+        };
+
+        let text_width = block.iter()
+            .chain(std::iter::once(&current_line))
+            .map(|line| styled_text_width(line, font_width))
+            .fold(0.0, f32::max);
+        let maximum_width = (window_width - font_width * 2.0).max(0.0);
+        let width = (text_width + font_width * (PADDING * 2.0 + 1.0))
+            .max(window_width * MINIMUM_WIDTH_PERCENTAGE)
+            .min(maximum_width);
+        let height = (block.len() as f32 + 1.0 + PADDING) * font_height;
+        let left = (window_width - width) / 2.0;
+
+        draw_bordered_panel(canvas, paint, Rect::new(left, top, left + width, top + height), background, border);
+
+        let text_left = left + PADDING * font_width;
+        let mut text_top = top + PADDING * font_height / 2.0;
+
+        canvas.save();
+        canvas.clip_rect(Rect::new(left, top, left + width, top + height), None, Some(false));
+
+        for line in block.iter() {
+            draw_styled_text(canvas, paint, shaper, line, Point::new(text_left, text_top), default_style, font_width, font_height);
+            text_top += font_height;
+        }
+
+        draw_styled_text(canvas, paint, shaper, &current_line, Point::new(text_left, text_top), default_style, font_width, font_height);
+
+        let cursor_left = text_left + level.cursor_column() as f32 * font_width;
+        if let Some((special_character, _)) = &level.special_character {
+            let special_character = vec![(None, special_character.clone())];
+            draw_styled_text(canvas, paint, shaper, &special_character, Point::new(cursor_left, text_top), default_style, font_width, font_height);
+        }
+
+        // Only the innermost level is being edited, so it is the only one which gets a cursor.
+        if level_index == command_line.levels.len() - 1 {
+            paint.set_color(foreground);
+            let cursor_width = (font_width * CURSOR_WIDTH_PERCENTAGE).max(1.0);
+            canvas.draw_rect(Rect::new(cursor_left, text_top, cursor_left + cursor_width, text_top + font_height), &paint);
+        }
+
+        canvas.restore();
+
+        text_origin = Some(Point::new(text_left, text_top));
+        top += height + LEVEL_SPACING * font_height;
+    }
+
+    text_origin
+}
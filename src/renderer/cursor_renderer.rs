@@ -1,15 +1,18 @@
 use std::time::{Duration, Instant};
 
-use skulpin::skia_safe::{Canvas, Paint, Path, Point};
+use skulpin::skia_safe::{Canvas, Paint, Path, Point, PaintStyle};
 
 use crate::renderer::CachingShaper;
-use crate::editor::{EDITOR, Colors, Cursor, CursorShape};
+use crate::editor::{Colors, Cursor, CursorCell, CursorShape};
 use crate::redraw_scheduler::REDRAW_SCHEDULER;
+use crate::settings::SETTINGS;
+use super::animation_utils::ease_out_cubic;
+use super::cursor_vfx::CursorVfx;
 
-const AVERAGE_MOTION_PERCENTAGE: f32 = 0.7;
-const MOTION_PERCENTAGE_SPREAD: f32 = 0.5;
 const COMMAND_LINE_DELAY_FRAMES: u64 = 5;
 const DEFAULT_CELL_PERCENTAGE: f32 = 1.0 / 8.0;
+const BLINK_FADE_MILLIS: u64 = 150;
+const UNFOCUSED_OUTLINE_WIDTH: f32 = 1.0;
 
 const STANDARD_CORNERS: &[(f32, f32); 4] = &[(-0.5, -0.5), (0.5, -0.5), (0.5, 0.5), (-0.5, 0.5)];
 
@@ -22,6 +25,9 @@ enum BlinkState {
 struct BlinkStatus {
     state: BlinkState,
     last_transition: Instant,
+    // Whether the cursor is coming back from being blinked off, as opposed to just having stopped
+    // waiting, in which case it is already fully visible.
+    fading_in: bool,
     previous_cursor: Option<Cursor>
 }
 
@@ -30,14 +36,17 @@ impl BlinkStatus {
         BlinkStatus {
             state: BlinkState::Waiting,
             last_transition: Instant::now(),
+            fading_in: false,
             previous_cursor: None
         }
     }
 
-    pub fn update_status(&mut self, new_cursor: &Cursor) -> bool {
+    // Returns how opaque the cursor should be drawn, from 0 for blinked off to 1 for fully on.
+    pub fn update_status(&mut self, new_cursor: &Cursor) -> f32 {
         if self.previous_cursor.is_none() || new_cursor != self.previous_cursor.as_ref().unwrap() {
             self.previous_cursor = Some(new_cursor.clone());
             self.last_transition = Instant::now();
+            self.fading_in = false;
             if new_cursor.blinkwait.is_some() && new_cursor.blinkwait != Some(0) {
                 self.state = BlinkState::Waiting;
             } else {
@@ -48,7 +57,7 @@ impl BlinkStatus {
         if new_cursor.blinkwait == Some(0) || 
             new_cursor.blinkoff == Some(0) ||
             new_cursor.blinkon == Some(0) {
-            return true;
+            return 1.0;
         }
 
         let delay = match self.state {
@@ -58,6 +67,10 @@ impl BlinkStatus {
         }.filter(|millis| *millis > 0).map(Duration::from_millis);
 
         if delay.map(|delay| self.last_transition + delay < Instant::now()).unwrap_or(false) {
+            self.fading_in = match self.state {
+                BlinkState::Off => true,
+                _ => false
+            };
             self.state = match self.state {
                 BlinkState::Waiting => BlinkState::On,
                 BlinkState::On => BlinkState::Off,
@@ -76,64 +89,109 @@ impl BlinkStatus {
             REDRAW_SCHEDULER.schedule(scheduled_frame);
         }
 
-        match self.state {
-            BlinkState::Waiting | BlinkState::Off => false,
-            BlinkState::On => true
+        let (fade_from, fade_to) = match self.state {
+            BlinkState::Waiting => return 1.0,
+            BlinkState::On if !self.fading_in => return 1.0,
+            BlinkState::On => (0.0, 1.0),
+            BlinkState::Off => (1.0, 0.0)
+        };
+
+        if !SETTINGS.get("cursor_blink_fade").read_bool() {
+            return fade_to;
+        }
+
+        // The fade is cut short for blink phases shorter than it, so that the cursor still fully
+        // reaches each state.
+        let phase_length = match self.state {
+            BlinkState::Off => new_cursor.blinkoff,
+            _ => new_cursor.blinkon
+        }.unwrap_or(BLINK_FADE_MILLIS);
+        let fade_length = Duration::from_millis(BLINK_FADE_MILLIS.min(phase_length)).as_secs_f32();
+        let progress = (self.last_transition.elapsed().as_secs_f32() / fade_length).min(1.0);
+        if progress < 1.0 {
+            REDRAW_SCHEDULER.queue_next_frame();
         }
+
+        fade_from + (fade_to - fade_from) * progress
     }
 }
 
 #[derive(Debug, Clone)]
 pub struct Corner {
+    pub start_position: Point,
     pub current_position: Point,
     pub relative_position: Point,
+    previous_destination: Point,
+    start_time: Instant,
+    animation_length: Duration
 }
 
 impl Corner {
     pub fn new(relative_position: Point) -> Corner {
         Corner {
+            start_position: Point::new(0.0, 0.0),
             current_position: Point::new(0.0, 0.0),
-            relative_position
+            relative_position,
+            previous_destination: Point::new(0.0, 0.0),
+            start_time: Instant::now(),
+            animation_length: Duration::from_millis(0)
         }
     }
 
-    pub fn update(&mut self, font_dimensions: Point, destination: Point) -> bool {
+    // Moves the corner toward the destination based on how much time passed since the
+    // destination last changed, so the motion looks the same no matter how often frames are
+    // drawn. The current time is passed in rather than read so that the motion is deterministic.
+    pub fn update(&mut self, font_dimensions: Point, destination: Point, now: Instant, animation_length: Duration, trail_size: f32) -> bool {
         let relative_scaled_position: Point = 
             (self.relative_position.x * font_dimensions.x, self.relative_position.y * font_dimensions.y).into();
         let corner_destination = destination + relative_scaled_position;
 
-        let delta = corner_destination - self.current_position;
-
-        if delta.length() > 0.0 {
-            // Project relative_scaled_position (actual possition of the corner relative to the
-            // center of the cursor) onto the remaining distance vector. This gives us the relative
-            // distance to the destination along the delta vector which we can then use to scale the
-            // motion_percentage.
-            let motion_scale = delta.dot(relative_scaled_position) / delta.length() / font_dimensions.length();
-
-            // The motion_percentage is then equal to the motion_scale factor times the
-            // MOTION_PERCENTAGE_SPREAD and added to the AVERAGE_MOTION_PERCENTAGE. This way all of
-            // the percentages are positive and spread out by the spread constant.
-            let motion_percentage = motion_scale * MOTION_PERCENTAGE_SPREAD + AVERAGE_MOTION_PERCENTAGE;
-
-            // Then the current_position is animated by taking the delta vector, multiplying it by
-            // the motion_percentage and adding the resulting value to the current position causing
-            // the cursor to "jump" toward the target destination. Since further away corners jump
-            // slower, the cursor appears to smear toward the destination in a satisfying and
-            // visually trackable way.
+        if corner_destination != self.previous_destination {
+            self.previous_destination = corner_destination;
+            self.start_position = self.current_position;
+            self.start_time = now;
+
             let delta = corner_destination - self.current_position;
-            self.current_position += delta * motion_percentage;
+            if delta.length() > 0.0 {
+                // Project relative_scaled_position (actual possition of the corner relative to the
+                // center of the cursor) onto the remaining distance vector. This gives us the
+                // relative distance to the destination along the delta vector, between -0.5 for
+                // the corners trailing behind and 0.5 for the ones leading the way.
+                let motion_scale = (delta.dot(relative_scaled_position) / delta.length() / font_dimensions.length())
+                    .max(-0.5).min(0.5);
+
+                // Leading corners then finish their motion sooner than trailing ones by up to the
+                // trail size. Since further away corners arrive later, the cursor appears to smear
+                // toward the destination in a satisfying and visually trackable way.
+                let length_factor = (1.0 - trail_size * (motion_scale + 0.5)).max(0.0);
+                self.animation_length = animation_length.mul_f32(length_factor);
+            }
         }
 
-        delta.length() > 0.001
+        let elapsed = now.saturating_duration_since(self.start_time).as_secs_f32();
+        let length = self.animation_length.as_secs_f32();
+        let progress = if length > 0.0 {
+            (elapsed / length).min(1.0)
+        } else {
+            1.0
+        };
+
+        let delta = corner_destination - self.start_position;
+        self.current_position = self.start_position + delta * ease_out_cubic(progress);
+
+        progress < 1.0
     }
 }
 
 pub struct CursorRenderer {
     pub corners: Vec<Corner>,
     pub previous_position: (u64, u64),
+    // Set when neovim draws the command line in the last row of the grid rather than sending
+    // it to the external command line.
+    pub command_line_in_grid: bool,
     pub command_line_delay: u64,
-    blink_status: BlinkStatus
+    blink_status: BlinkStatus,
+    vfx: CursorVfx
 }
 
 impl CursorRenderer {
@@ -141,13 +199,31 @@ impl CursorRenderer {
         let mut renderer = CursorRenderer {
             corners: vec![Corner::new((0.0, 0.0).into()); 4],
             previous_position: (0, 0),
+            command_line_in_grid: false,
             command_line_delay: 0,
-            blink_status: BlinkStatus::new()
+            blink_status: BlinkStatus::new(),
+            vfx: CursorVfx::new()
         };
         renderer.set_cursor_shape(&CursorShape::Block, DEFAULT_CELL_PERCENTAGE);
         renderer
     }
 
+    // When the command line is drawn in the grid, neovim briefly moves the cursor to the last row
+    // on every redraw. Following it there is delayed for a few frames to avoid flicker.
+    fn delay_command_line_jump(&mut self, position: (u64, u64), grid_height: u64) -> (u64, u64) {
+        let (_, grid_y) = position;
+        let (_, previous_y) = self.previous_position;
+        if grid_y == grid_height - 1 && previous_y != grid_y {
+            self.command_line_delay += 1;
+            if self.command_line_delay < COMMAND_LINE_DELAY_FRAMES {
+                return self.previous_position;
+            }
+        }
+
+        self.command_line_delay = 0;
+        position
+    }
+
     fn set_cursor_shape(&mut self, cursor_shape: &CursorShape, cell_percentage: f32) {
         self.corners = self.corners
             .clone()
@@ -172,70 +248,77 @@ impl CursorRenderer {
     }
 
     pub fn draw(&mut self, 
-            cursor: Cursor, default_colors: &Colors, 
+            cursor: Cursor, cursor_cell: &CursorCell, grid_height: u64,
+            default_colors: &Colors, focused: bool,
             font_width: f32, font_height: f32,
             paint: &mut Paint, shaper: &mut CachingShaper, 
             canvas: &mut Canvas) {
-        let render = self.blink_status.update_status(&cursor);
-
-        self.previous_position = {
-            let editor = EDITOR.lock();
-            let (_, grid_y) = cursor.position;
-            let (_, previous_y) = self.previous_position;
-            if grid_y == editor.grid.height - 1 && previous_y != grid_y {
-                self.command_line_delay += 1;
-                if self.command_line_delay < COMMAND_LINE_DELAY_FRAMES {
-                    self.previous_position
-                } else {
-                    self.command_line_delay = 0;
-                    cursor.position
-                }
-            } else {
-                self.command_line_delay = 0;
-                cursor.position
-            }
+        // Blinking only makes sense while the window has focus. Otherwise the cursor is always
+        // drawn, just as an outline.
+        let opacity = if focused {
+            self.blink_status.update_status(&cursor)
+        } else {
+            1.0
         };
 
-        let (grid_x, grid_y) = self.previous_position;
+        self.previous_position = if self.command_line_in_grid {
+            self.delay_command_line_jump(cursor.position, grid_height)
+        } else {
+            cursor.position
+        };
 
-        let (character, font_dimensions): (String, Point) = {
-            let editor = EDITOR.lock();
-            let character = match editor.grid.get_cell(grid_x, grid_y) {
-                Some(Some((character, _))) => character.clone(),
-                _ => ' '.to_string(),
-            };
-            
-            let is_double = match editor.grid.get_cell(grid_x + 1, grid_y) {
-                Some(Some((character, _))) => character.is_empty(),
-                _ => false,
-            };
+        let (grid_x, grid_y) = self.previous_position;
 
-            let font_width = match (is_double, &cursor.shape) {
-                (true, CursorShape::Block) => font_width * 2.0,
+        let font_dimensions: Point = {
+            // Bars are the same width no matter what is under them, while blocks and underlines
+            // cover the whole character.
+            let font_width = match (cursor_cell.double_width, &cursor.shape) {
+                (true, CursorShape::Vertical) => font_width,
+                (true, _) => font_width * 2.0,
                 _ => font_width
             };
-            (character, (font_width, font_height).into())
+            (font_width, font_height).into()
         };
         let destination: Point = (grid_x as f32 * font_width, grid_y as f32 * font_height).into();
         let center_destination = destination + font_dimensions * 0.5;
 
         self.set_cursor_shape(&cursor.shape, cursor.cell_percentage.unwrap_or(DEFAULT_CELL_PERCENTAGE));
 
+        let now = Instant::now();
+        let animation_length = Duration::from_millis(SETTINGS.get("cursor_animation_length").read_u16() as u64);
+        let trail_size = SETTINGS.get("cursor_trail_size").read_f32().max(0.0).min(1.0);
+
         let mut animating = false;
         if !center_destination.is_zero() {
             for corner in self.corners.iter_mut() {
-                let corner_animating = corner.update(font_dimensions, center_destination);
+                let corner_animating = corner.update(font_dimensions, center_destination, now, animation_length, trail_size);
                 animating = animating || corner_animating;
             }
         }
 
-        if animating || self.command_line_delay != 0 {
+        // Particles follow the center of the cursor as it smears across the screen rather than
+        // its destination, so that they are emitted along the whole path.
+        let cursor_center = self.corners.iter()
+            .fold(Point::new(0.0, 0.0), |sum, corner| sum + corner.current_position) * (1.0 / self.corners.len() as f32);
+        let particles_alive = self.vfx.update(cursor_center, font_dimensions, now);
+
+        if animating || particles_alive || self.command_line_delay != 0 {
             REDRAW_SCHEDULER.queue_next_frame();
         }
 
-        if cursor.enabled && render {
+        // Without a highlight of its own, the cursor inverts the colors of the cell under it.
+        let (background, foreground) = match (&cursor.style, &cursor_cell.style) {
+            (None, Some(cell_style)) => (cell_style.foreground(&default_colors), cell_style.background(&default_colors)),
+            _ => (cursor.background(&default_colors), cursor.foreground(&default_colors))
+        };
+
+        self.vfx.draw(canvas, paint, background.to_color(), font_dimensions);
+
+        if cursor.enabled && opacity > 0.0 {
+            let alpha = (opacity * 255.0) as u8;
+
             // Draw Background
-            paint.set_color(cursor.background(&default_colors).to_color());
+            paint.set_color(background.to_color().with_a(alpha));
 
             // The cursor is made up of four points, so I create a path with each of the four
             // corners.
@@ -245,18 +328,124 @@ impl CursorRenderer {
             path.line_to(self.corners[2].current_position);
             path.line_to(self.corners[3].current_position);
             path.close();
+
+            // An unfocused cursor is just an outline which leaves the character under it alone.
+            if !focused {
+                paint.set_style(PaintStyle::Stroke);
+                paint.set_stroke_width(UNFOCUSED_OUTLINE_WIDTH);
+                canvas.draw_path(&path, &paint);
+                paint.set_style(PaintStyle::Fill);
+                return;
+            }
+
             canvas.draw_path(&path, &paint);
 
             // Draw foreground
-            paint.set_color(cursor.foreground(&default_colors).to_color());
+            paint.set_color(foreground.to_color().with_a(alpha));
             canvas.save();
             canvas.clip_path(&path, None, Some(false));
-            
-            let blobs = &shaper.shape_cached(&character, false, false);
+
+            let (bold, italic, underline) = cursor_cell.style.as_ref()
+                .map(|style| (style.bold, style.italic, style.underline || style.undercurl))
+                .unwrap_or((false, false, false));
+
+            // Shaping the whole run keeps ligatures intact under the cursor. The clip cuts it
+            // down to just the part the cursor covers.
+            let (text, text_position) = if SETTINGS.get("cursor_ligatures").read_bool() {
+                let run_left = destination.x - cursor_cell.ligature_offset as f32 * font_width;
+                (&cursor_cell.ligature_run, Point::new(run_left, destination.y))
+            } else {
+                (&cursor_cell.character, destination)
+            };
+
+            let blobs = &shaper.shape_cached(text, bold, italic);
             for blob in blobs.iter() {
-                canvas.draw_text_blob(&blob, destination, &paint);
+                canvas.draw_text_blob(&blob, text_position, &paint);
+            }
+
+            if underline {
+                let line_y = destination.y - shaper.underline_position() + font_height;
+                paint.set_stroke_width(shaper.base_size / 10.0);
+                paint.set_path_effect(None);
+                canvas.draw_line((destination.x, line_y), (destination.x + font_dimensions.x, line_y), &paint);
             }
             canvas.restore();
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FONT_DIMENSIONS: (f32, f32) = (10.0, 20.0);
+    const DESTINATION: (f32, f32) = (300.0, 120.0);
+
+    // A corner resting at the origin, with the clock starting when it is told to move.
+    fn resting_corner(relative_position: (f32, f32)) -> (Corner, Instant) {
+        let start = Instant::now();
+        let mut corner = Corner::new(relative_position.into());
+        corner.update(FONT_DIMENSIONS.into(), Point::new(0.0, 0.0), start, Duration::from_millis(0), 0.0);
+        (corner, start)
+    }
+
+    // Moves a corner toward DESTINATION drawing frames at the given rate for a second, and
+    // returns where it was at each of the requested times.
+    fn sample_motion(frames_per_second: u64, times: &[Duration]) -> Vec<Point> {
+        let animation_length = Duration::from_millis(500);
+        let (mut corner, start) = resting_corner((-0.5, -0.5));
+        corner.update(FONT_DIMENSIONS.into(), DESTINATION.into(), start, animation_length, 0.5);
+
+        let mut positions = Vec::new();
+        for frame in 1..=frames_per_second {
+            let elapsed = Duration::from_nanos(1_000_000_000 * frame / frames_per_second);
+            corner.update(FONT_DIMENSIONS.into(), DESTINATION.into(), start + elapsed, animation_length, 0.5);
+            if times.contains(&elapsed) {
+                positions.push(corner.current_position);
+            }
+        }
+        positions
+    }
+
+    #[test]
+    fn corner_arrives_after_exactly_the_animation_length() {
+        let animation_length = Duration::from_millis(150);
+        let (mut corner, start) = resting_corner((0.0, 0.0));
+
+        assert!(corner.update(FONT_DIMENSIONS.into(), DESTINATION.into(), start, animation_length, 0.0));
+        assert_eq!(corner.current_position, Point::new(0.0, 0.0));
+
+        let almost = start + animation_length - Duration::from_millis(1);
+        assert!(corner.update(FONT_DIMENSIONS.into(), DESTINATION.into(), almost, animation_length, 0.0));
+        assert_ne!(corner.current_position, Point::from(DESTINATION));
+
+        assert!(!corner.update(FONT_DIMENSIONS.into(), DESTINATION.into(), start + animation_length, animation_length, 0.0));
+        assert_eq!(corner.current_position, Point::from(DESTINATION));
+    }
+
+    #[test]
+    fn trailing_corners_still_arrive_within_the_animation_length() {
+        let animation_length = Duration::from_millis(150);
+        for relative_position in STANDARD_CORNERS.iter() {
+            let (mut corner, start) = resting_corner(*relative_position);
+            corner.update(FONT_DIMENSIONS.into(), DESTINATION.into(), start, animation_length, 0.8);
+
+            assert!(!corner.update(FONT_DIMENSIONS.into(), DESTINATION.into(), start + animation_length, animation_length, 0.8));
+            let relative_scaled_position = Point::new(relative_position.0 * FONT_DIMENSIONS.0, relative_position.1 * FONT_DIMENSIONS.1);
+            assert_eq!(corner.current_position, Point::from(DESTINATION) + relative_scaled_position);
+        }
+    }
+
+    #[test]
+    fn motion_doesnt_depend_on_the_frame_rate() {
+        // Every frame rate below draws a frame at each of these times.
+        let times = [Duration::from_nanos(166_666_666), Duration::from_nanos(333_333_333), Duration::from_secs(1)];
+
+        let at_60_hz = sample_motion(60, &times);
+        assert_eq!(at_60_hz.len(), times.len());
+        assert!(at_60_hz[0].x > 0.0 && at_60_hz[0].x < at_60_hz[1].x && at_60_hz[1].x < at_60_hz[2].x);
+
+        assert_eq!(sample_motion(30, &times), at_60_hz);
+        assert_eq!(sample_motion(144, &times), at_60_hz);
+    }
+}
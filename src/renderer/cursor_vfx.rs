@@ -0,0 +1,232 @@
+use std::time::{Duration, Instant};
+
+use skulpin::skia_safe::{Canvas, Paint, Point, Color};
+
+use crate::settings::SETTINGS;
+
+// Particles are simulated in fixed steps so that their motion doesn't depend on the frame rate.
+const SIMULATION_STEP: f32 = 1.0 / 120.0;
+// Frames can be far apart after the ui loop idles, but there is no point catching up on more
+// than this much simulation at once.
+const MAXIMUM_FRAME_TIME: f32 = 0.1;
+const PARTICLE_RADIUS: f32 = 0.15;
+const TORPEDO_SPREAD: f32 = 0.5;
+const PIXIEDUST_DRIFT: f32 = 1.5;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VfxMode {
+    Railgun,
+    Torpedo,
+    Pixiedust
+}
+
+impl VfxMode {
+    fn from_setting(mode: &str) -> Option<VfxMode> {
+        match mode {
+            "railgun" => Some(VfxMode::Railgun),
+            "torpedo" => Some(VfxMode::Torpedo),
+            "pixiedust" => Some(VfxMode::Pixiedust),
+            _ => None
+        }
+    }
+}
+
+// Xorshift is plenty random enough to scatter particles and saves pulling in a dependency.
+struct RngState {
+    state: u32
+}
+
+impl RngState {
+    fn new() -> RngState {
+        RngState { state: 0x9e37_79b9 }
+    }
+
+    fn next_u32(&mut self) -> u32 {
+        let mut x = self.state;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.state = x;
+        x
+    }
+
+    // Uniformly distributed between -1 and 1.
+    fn next_f32(&mut self) -> f32 {
+        (self.next_u32() as f64 / std::u32::MAX as f64 * 2.0 - 1.0) as f32
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Particle {
+    position: Point,
+    velocity: Point,
+    // Seconds left to live.
+    lifetime: f32
+}
+
+// Settings read once per frame so that they don't have to be looked up for every particle.
+struct VfxSettings {
+    lifetime: f32,
+    density: f32,
+    opacity: f32,
+    speed: f32,
+    variation: f32
+}
+
+impl VfxSettings {
+    fn read() -> VfxSettings {
+        VfxSettings {
+            lifetime: SETTINGS.get("cursor_vfx_particle_lifetime").read_f32().max(0.0),
+            density: SETTINGS.get("cursor_vfx_particle_density").read_f32().max(0.0),
+            opacity: SETTINGS.get("cursor_vfx_opacity").read_f32().max(0.0).min(255.0),
+            speed: SETTINGS.get("cursor_vfx_particle_speed").read_f32(),
+            variation: SETTINGS.get("cursor_vfx_particle_variation").read_f32().max(0.0)
+        }
+    }
+}
+
+// Emits particles along the path the cursor travels and simulates them until they die out.
+pub struct CursorVfx {
+    particles: Vec<Particle>,
+    rng: RngState,
+    previous_center: Option<Point>,
+    // Fraction of a particle left over from the last emission, so that slow movement still emits
+    // particles eventually.
+    emit_remainder: f32,
+    // Path length travelled so far, which railgun particles use to spiral around the path.
+    travelled: f32,
+    unsimulated_time: f32,
+    last_update: Option<Instant>
+}
+
+impl CursorVfx {
+    pub fn new() -> CursorVfx {
+        CursorVfx {
+            particles: Vec::new(),
+            rng: RngState::new(),
+            previous_center: None,
+            emit_remainder: 0.0,
+            travelled: 0.0,
+            unsimulated_time: 0.0,
+            last_update: None
+        }
+    }
+
+    fn emit(&mut self, mode: VfxMode, from: Point, to: Point, font_dimensions: Point, settings: &VfxSettings) {
+        let travel = to - from;
+        let distance = travel.length();
+        if distance <= 0.0 {
+            return;
+        }
+
+        let direction = travel * (1.0 / distance);
+        let normal = Point::new(-direction.y, direction.x);
+        let speed = settings.speed * font_dimensions.x;
+
+        let count = distance / font_dimensions.x * settings.density + self.emit_remainder;
+        self.emit_remainder = count.fract();
+
+        let whole_count = count as usize;
+        for index in 0..whole_count {
+            let t = (index as f32 + 1.0) / whole_count as f32;
+            let variation = 1.0 + self.rng.next_f32() * settings.variation;
+
+            let (position, velocity) = match mode {
+                // Particles shoot out sideways from the path, alternating direction as it goes so
+                // that they trace a spiral around it.
+                VfxMode::Railgun => {
+                    let phase = (self.travelled + distance * t) / font_dimensions.x;
+                    (from + travel * t, normal * (phase.sin() * speed * variation))
+                },
+                // Particles spray backward out of the end of the cursor like a wake.
+                VfxMode::Torpedo => {
+                    let spread = normal * (self.rng.next_f32() * TORPEDO_SPREAD);
+                    (to - direction * (font_dimensions.x / 2.0), (spread - direction) * (speed * variation))
+                },
+                // Particles scatter lightly in every direction and drift downward.
+                VfxMode::Pixiedust => {
+                    let scatter = Point::new(self.rng.next_f32(), self.rng.next_f32());
+                    let offset = Point::new(self.rng.next_f32() * font_dimensions.x / 2.0, self.rng.next_f32() * font_dimensions.y / 2.0);
+                    (from + travel * t + offset, scatter * (speed * variation * 0.25))
+                }
+            };
+
+            let lifetime = settings.lifetime * (1.0 + self.rng.next_f32() * settings.variation * 0.5).max(0.1);
+            self.particles.push(Particle { position, velocity, lifetime });
+        }
+
+        self.travelled += distance;
+    }
+
+    fn step(&mut self, mode: VfxMode, font_dimensions: Point) {
+        for particle in self.particles.iter_mut() {
+            if mode == VfxMode::Pixiedust {
+                particle.velocity.y += PIXIEDUST_DRIFT * font_dimensions.y * SIMULATION_STEP;
+            }
+            particle.position += particle.velocity * SIMULATION_STEP;
+            particle.lifetime -= SIMULATION_STEP;
+        }
+        self.particles.retain(|particle| particle.lifetime > 0.0);
+    }
+
+    // Emits particles for the distance the cursor moved since the last frame and advances the
+    // simulation. Returns true while particles are still alive and need more frames.
+    pub fn update(&mut self, cursor_center: Point, font_dimensions: Point, now: Instant) -> bool {
+        let mode = match VfxMode::from_setting(&SETTINGS.get("cursor_vfx_mode").read_string()) {
+            Some(mode) => mode,
+            None => {
+                self.particles.clear();
+                self.previous_center = None;
+                self.last_update = None;
+                return false;
+            }
+        };
+        let settings = VfxSettings::read();
+
+        if let Some(previous_center) = self.previous_center {
+            self.emit(mode, previous_center, cursor_center, font_dimensions, &settings);
+        }
+        self.previous_center = Some(cursor_center);
+
+        let frame_time = self.last_update
+            .map(|last_update| now.saturating_duration_since(last_update))
+            .unwrap_or_else(|| Duration::from_millis(0))
+            .as_secs_f32()
+            .min(MAXIMUM_FRAME_TIME);
+        self.last_update = Some(now);
+
+        self.unsimulated_time += frame_time;
+        while self.unsimulated_time >= SIMULATION_STEP {
+            self.step(mode, font_dimensions);
+            self.unsimulated_time -= SIMULATION_STEP;
+        }
+
+        if self.particles.is_empty() {
+            self.travelled = 0.0;
+            false
+        } else {
+            true
+        }
+    }
+
+    // Draws the particles in the given color, fading and shrinking them as they die out.
+    pub fn draw(&self, canvas: &mut Canvas, paint: &mut Paint, color: Color, font_dimensions: Point) {
+        if self.particles.is_empty() {
+            return;
+        }
+
+        let settings = VfxSettings::read();
+        paint.set_anti_alias(true);
+        for particle in self.particles.iter() {
+            let life = if settings.lifetime > 0.0 {
+                (particle.lifetime / settings.lifetime).min(1.0)
+            } else {
+                0.0
+            };
+            let alpha = (settings.opacity * life) as u8;
+            paint.set_color(Color::from_argb(alpha, color.r(), color.g(), color.b()));
+            canvas.draw_circle(particle.position, PARTICLE_RADIUS * font_dimensions.x * (0.5 + life / 2.0), &paint);
+        }
+        paint.set_anti_alias(false);
+    }
+}
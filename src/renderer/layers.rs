@@ -0,0 +1,265 @@
+use skulpin::skia_safe::{Canvas, Paint, Rect, RRect, Color, BlurStyle, MaskFilter, blur_image_filter};
+use skulpin::skia_safe::canvas::SaveLayerRec;
+
+use crate::editor::{WindowFrame, WindowKind};
+use crate::settings::SETTINGS;
+
+const SHADOW_OPACITY: u8 = 96;
+
+// Sizes in pixels of the effects drawn around floating windows. Zero turns an effect off.
+#[derive(Debug, Clone, Copy)]
+pub struct FloatingEffects {
+    pub corner_radius: f32,
+    pub shadow_size: f32,
+    pub blur_amount: f32
+}
+
+impl FloatingEffects {
+    pub fn from_settings() -> FloatingEffects {
+        FloatingEffects {
+            corner_radius: SETTINGS.get("floating_corner_radius").read_u16() as f32,
+            shadow_size: SETTINGS.get("floating_shadow").read_u16() as f32,
+            blur_amount: SETTINGS.get("floating_blur").read_u16() as f32
+        }
+    }
+}
+
+// A window's place in the stack of grids drawn into the main window.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Layer {
+    pub grid_id: u64,
+    pub destination: Rect,
+    pub floating: bool
+}
+
+// Lays out the windows of a frame from the bottom of the stack to the top, which is the order
+// the editor lists them in. Hidden windows and ones with an OS window of their own aren't part of
+// the stack. Rows start below top, which leaves room for the tabline.
+pub fn window_layers(windows: &[WindowFrame], font_width: f32, font_height: f32, top: f32) -> Vec<Layer> {
+    windows.iter()
+        .filter(|window| !window.hidden && window.kind != WindowKind::External)
+        .map(|window| {
+            let (left, row) = window.position;
+            let (width, height) = window.size;
+            let x = left as f32 * font_width;
+            let y = row as f32 * font_height + top;
+            Layer {
+                grid_id: window.grid_id,
+                destination: Rect::new(x, y, x + width as f32 * font_width, y + height as f32 * font_height),
+                floating: window.kind == WindowKind::Floating
+            }
+        })
+        .collect()
+}
+
+// Draws the layers bottom to top, so each covers the ones before it. The contents of each layer
+// are drawn by draw_contents.
+pub fn draw_layers<F: FnMut(&mut Canvas, &Layer)>(canvas: &mut Canvas, layers: &[Layer], effects: &FloatingEffects, mut draw_contents: F) {
+    for layer in layers {
+        if layer.floating {
+            draw_floating_window(canvas, layer.destination, effects, |canvas| draw_contents(canvas, layer));
+        } else {
+            draw_contents(canvas, layer);
+        }
+    }
+}
+
+// Draws a floating window as a layer above the windows it covers. The layer gets a soft drop
+// shadow, rounded corners and optionally blurs whatever is behind it, which only shows through
+// where the float's highlights set a blend. The float's contents are drawn by draw_contents
+// inside the layer's clip.
+fn draw_floating_window<F: FnOnce(&mut Canvas)>(canvas: &mut Canvas, destination: Rect, effects: &FloatingEffects, draw_contents: F) {
+    let FloatingEffects { corner_radius, shadow_size, blur_amount } = *effects;
+
+    let layer_shape = RRect::new_rect_xy(destination, corner_radius, corner_radius);
+
+    if shadow_size > 0.0 {
+        let mut shadow_paint = Paint::default();
+        shadow_paint.set_anti_alias(true);
+        shadow_paint.set_color(Color::from_argb(SHADOW_OPACITY, 0, 0, 0));
+        shadow_paint.set_mask_filter(MaskFilter::blur(BlurStyle::Normal, shadow_size / 2.0, None));
+
+        // Offsetting the shadow downward makes the float look like it is lit from above.
+        let shadow_shape = RRect::new_rect_xy(destination.with_offset((0.0, shadow_size / 2.0)), corner_radius, corner_radius);
+        canvas.draw_rrect(shadow_shape, &shadow_paint);
+    }
+
+    canvas.save();
+    canvas.clip_rrect(layer_shape, None, Some(true));
+
+    if blur_amount > 0.0 {
+        if let Some(blur) = blur_image_filter::new((blur_amount, blur_amount), None, None, None) {
+            let backdrop = SaveLayerRec::default()
+                .bounds(&destination)
+                .backdrop(&blur);
+            canvas.save_layer(&backdrop);
+            canvas.restore();
+        }
+    }
+
+    draw_contents(canvas);
+    canvas.restore();
+}
+
+#[cfg(test)]
+mod tests {
+    use skulpin::skia_safe::{Surface, ImageInfo, ColorType, AlphaType};
+
+    use crate::bridge::{RedrawEvent, WindowAnchor, GridLineCell};
+    use crate::editor::Editor;
+    use super::*;
+
+    const CELL_SIZE: f32 = 10.0;
+    const NO_EFFECTS: FloatingEffects = FloatingEffects { corner_radius: 0.0, shadow_size: 0.0, blur_amount: 0.0 };
+
+    fn resize(grid: u64, width: u64, height: u64) -> RedrawEvent {
+        RedrawEvent::Resize { grid, width, height }
+    }
+
+    fn float(grid: u64, row: f64, column: f64) -> RedrawEvent {
+        RedrawEvent::WindowFloatPosition {
+            grid, window: grid + 1000, anchor: WindowAnchor::NorthWest,
+            anchor_grid: 1, anchor_row: row, anchor_column: column, focusable: true
+        }
+    }
+
+    // A 10 by 8 cell screen with a split in the top half, two overlapping floats and the message
+    // area at the bottom which the lower float reaches into. Events arrive in a different order
+    // than the windows stack in.
+    fn editor() -> Editor {
+        let mut editor = Editor::new();
+        for event in vec![
+            resize(1, 10, 8),
+            resize(3, 4, 3),
+            float(3, 2.0, 2.0),
+            resize(5, 10, 2),
+            RedrawEvent::MessageSetPosition { grid: 5, row: 6, scrolled: false, separator_character: String::new() },
+            resize(4, 4, 4),
+            float(4, 3.0, 4.0),
+            resize(2, 10, 4),
+            RedrawEvent::WindowPosition { grid: 2, window: 1002, start_row: 0, start_column: 0, width: 10, height: 4 },
+            RedrawEvent::GridLine {
+                grid: 3, row: 0, column_start: 0,
+                cells: vec![GridLineCell { text: "x".to_string(), highlight_id: None, repeat: Some(4) }]
+            },
+            RedrawEvent::Flush
+        ] {
+            editor.handle_redraw_event(event);
+        }
+        editor
+    }
+
+    fn stack(editor: &mut Editor) -> Vec<Layer> {
+        window_layers(&editor.take_render_frame().windows, CELL_SIZE, CELL_SIZE, 0.0)
+    }
+
+    fn grid_color(grid_id: u64) -> Color {
+        match grid_id {
+            1 => Color::RED,
+            2 => Color::GREEN,
+            3 => Color::BLUE,
+            4 => Color::YELLOW,
+            _ => Color::MAGENTA
+        }
+    }
+
+    // Composites the layers on the cpu with every grid a solid color, so no gpu is needed.
+    fn render(layers: &[Layer], effects: &FloatingEffects) -> Surface {
+        let mut surface = Surface::new_raster_n32_premul((100, 100)).expect("Could not create raster surface");
+        let canvas = surface.canvas();
+        canvas.clear(Color::BLACK);
+        let mut paint = Paint::default();
+        draw_layers(canvas, layers, effects, |canvas, layer| {
+            paint.set_color(grid_color(layer.grid_id));
+            canvas.draw_rect(layer.destination, &paint);
+        });
+        surface
+    }
+
+    fn pixel(surface: &mut Surface, x: i32, y: i32) -> Color {
+        let info = ImageInfo::new((1, 1), ColorType::RGBA8888, AlphaType::Premul, None);
+        let mut pixel = [0; 4];
+        assert!(surface.read_pixels(&info, &mut pixel, 4, (x, y)));
+        Color::from_argb(pixel[3], pixel[0], pixel[1], pixel[2])
+    }
+
+    #[test]
+    fn windows_stack_by_kind_then_by_when_they_were_positioned() {
+        let layers = stack(&mut editor());
+        let grids: Vec<u64> = layers.iter().map(|layer| layer.grid_id).collect();
+        assert_eq!(grids, vec![1, 2, 3, 4, 5]);
+        assert_eq!(layers[2].destination, Rect::new(20.0, 20.0, 60.0, 50.0));
+        assert!(layers[2].floating && layers[3].floating);
+        assert!(!layers[0].floating && !layers[1].floating && !layers[4].floating);
+    }
+
+    #[test]
+    fn repositioned_floats_move_to_the_top() {
+        let mut editor = editor();
+        editor.handle_redraw_event(float(3, 2.0, 2.0));
+        editor.handle_redraw_event(RedrawEvent::Flush);
+        let grids: Vec<u64> = stack(&mut editor).iter().map(|layer| layer.grid_id).collect();
+        assert_eq!(grids, vec![1, 2, 4, 3, 5]);
+    }
+
+    #[test]
+    fn hidden_windows_are_left_out() {
+        let mut editor = editor();
+        editor.handle_redraw_event(RedrawEvent::WindowHide { grid: 4 });
+        editor.handle_redraw_event(RedrawEvent::Flush);
+        let grids: Vec<u64> = stack(&mut editor).iter().map(|layer| layer.grid_id).collect();
+        assert_eq!(grids, vec![1, 2, 3, 5]);
+    }
+
+    #[test]
+    fn layers_cover_the_ones_below_them() {
+        let mut surface = render(&stack(&mut editor()), &NO_EFFECTS);
+        // The split covers the default grid.
+        assert_eq!(pixel(&mut surface, 5, 5), grid_color(2));
+        assert_eq!(pixel(&mut surface, 5, 45), grid_color(1));
+        // Floats cover the split, and the later one covers the earlier.
+        assert_eq!(pixel(&mut surface, 25, 25), grid_color(3));
+        assert_eq!(pixel(&mut surface, 50, 40), grid_color(4));
+        assert_eq!(pixel(&mut surface, 75, 45), grid_color(4));
+        // Messages cover floats.
+        assert_eq!(pixel(&mut surface, 50, 65), grid_color(5));
+    }
+
+    #[test]
+    fn repositioned_floats_are_drawn_on_top() {
+        let mut editor = editor();
+        editor.handle_redraw_event(float(3, 2.0, 2.0));
+        editor.handle_redraw_event(RedrawEvent::Flush);
+        let mut surface = render(&stack(&mut editor), &NO_EFFECTS);
+        assert_eq!(pixel(&mut surface, 50, 40), grid_color(3));
+        assert_eq!(pixel(&mut surface, 75, 45), grid_color(4));
+    }
+
+    #[test]
+    fn shadows_darken_what_is_below_a_float() {
+        let effects = FloatingEffects { corner_radius: 0.0, shadow_size: 8.0, blur_amount: 0.0 };
+        let layers = vec![
+            Layer { grid_id: 1, destination: Rect::new(0.0, 0.0, 100.0, 100.0), floating: false },
+            Layer { grid_id: 3, destination: Rect::new(10.0, 10.0, 60.0, 60.0), floating: true }
+        ];
+        let mut surface = render(&layers, &effects);
+        assert_eq!(pixel(&mut surface, 30, 30), grid_color(3));
+        assert_eq!(pixel(&mut surface, 95, 5), grid_color(1));
+        let shadowed = pixel(&mut surface, 30, 63);
+        assert!(shadowed.r() > 128 && shadowed.r() < 255, "red was {}", shadowed.r());
+        assert_eq!((shadowed.g(), shadowed.b()), (0, 0));
+    }
+
+    #[test]
+    fn floats_are_clipped_to_their_rounded_corners() {
+        let effects = FloatingEffects { corner_radius: 4.0, shadow_size: 0.0, blur_amount: 0.0 };
+        let layers = vec![
+            Layer { grid_id: 1, destination: Rect::new(0.0, 0.0, 100.0, 100.0), floating: false },
+            Layer { grid_id: 3, destination: Rect::new(10.0, 10.0, 60.0, 60.0), floating: true }
+        ];
+        let mut surface = render(&layers, &effects);
+        assert_eq!(pixel(&mut surface, 10, 10), grid_color(1));
+        assert_eq!(pixel(&mut surface, 59, 59), grid_color(1));
+        assert_eq!(pixel(&mut surface, 12, 30), grid_color(3));
+    }
+}
@@ -0,0 +1,193 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use skulpin::skia_safe::{Canvas, Paint, Point, Rect, Color};
+
+use crate::bridge::MessageKind;
+use crate::editor::{Messages, MessageStyles, Style, StyledText};
+use crate::redraw_scheduler::REDRAW_SCHEDULER;
+use crate::settings::SETTINGS;
+use super::CachingShaper;
+use super::overlay::{draw_bordered_panel, draw_styled_text, styled_text_width, wrap_styled_text};
+
+const MAXIMUM_WIDTH_PERCENTAGE: f32 = 0.5;
+const MARGIN: f32 = 1.0;
+const PADDING: f32 = 1.0;
+const SPACING: f32 = 0.5;
+const ACCENT_WIDTH: f32 = 3.0;
+
+// The color which makes a highlight group stand out. Groups with a background of their own, like
+// neovim's default ErrorMsg and Search, are recognized by it and the rest by their foreground.
+fn accent_color(style: &Style) -> Option<Color> {
+    let (foreground, background) = if style.reverse {
+        (&style.colors.background, &style.colors.foreground)
+    } else {
+        (&style.colors.foreground, &style.colors.background)
+    };
+    background.as_ref().or_else(|| foreground.as_ref()).map(|color| color.to_color())
+}
+
+fn mix(first: Color, second: Color) -> Color {
+    let average = |first: u8, second: u8| ((first as u16 + second as u16) / 2) as u8;
+    Color::from_rgb(average(first.r(), second.r()), average(first.g(), second.g()), average(first.b(), second.b()))
+}
+
+// Accents follow the colorscheme's message highlights, and the fixed colors are only used when
+// the groups aren't defined. There is no group for Lua errors, so theirs is the error color
+// pulled toward purple to tell them apart from other errors.
+fn kind_color(kind: &MessageKind, styles: &MessageStyles, default_color: Color) -> Color {
+    let style_color = |style: &Option<Arc<Style>>, fallback: Color| style.as_ref()
+        .and_then(|style| accent_color(style))
+        .unwrap_or(fallback);
+
+    match kind {
+        MessageKind::Error | MessageKind::EchoError | MessageKind::RpcError => style_color(&styles.error, Color::from_rgb(224, 82, 82)),
+        MessageKind::Warning => style_color(&styles.warning, Color::from_rgb(229, 192, 90)),
+        MessageKind::LuaError => mix(style_color(&styles.error, Color::from_rgb(224, 82, 82)), Color::from_rgb(198, 120, 221)),
+        MessageKind::SearchCount => style_color(&styles.search, Color::from_rgb(97, 175, 239)),
+        _ => default_color
+    }
+}
+
+struct Panel {
+    lines: Vec<StyledText>,
+    accent: Color
+}
+
+// Draws neovim's messages as a stack of notifications in the bottom right corner of the window with
+// showmode, showcmd and the ruler in a small strip underneath them.
+pub fn draw_messages(
+        messages: &Messages, default_style: &Arc<Style>,
+        canvas: &mut Canvas, paint: &mut Paint, shaper: &mut CachingShaper,
+        font_width: f32, font_height: f32,
+        window_width: f32, window_height: f32) {
+    let background = default_style.colors.background.clone().unwrap().to_color();
+    let foreground = default_style.colors.foreground.clone().unwrap().to_color();
+    let border = Color::from_argb(96, foreground.r(), foreground.g(), foreground.b());
+
+    let maximum_width = window_width * MAXIMUM_WIDTH_PERCENTAGE;
+    let maximum_columns = ((maximum_width / font_width) - PADDING * 2.0).max(1.0) as usize;
+    let right = window_width - MARGIN * font_width;
+    let mut bottom = window_height - MARGIN * font_height;
+
+    if !messages.status_is_empty() {
+        let mut status: StyledText = Vec::new();
+        for content in [&messages.show_mode, &messages.show_command, &messages.ruler].iter() {
+            if content.iter().any(|(_, text)| !text.trim().is_empty()) {
+                if !status.is_empty() {
+                    status.push((None, "  ".to_string()));
+                }
+                status.extend(content.iter().cloned());
+            }
+        }
+
+        let width = styled_text_width(&status, font_width) + PADDING * font_width;
+        let top = bottom - font_height;
+        let left = right - width;
+        draw_bordered_panel(canvas, paint, Rect::new(left, top, right, bottom), background, border);
+        draw_styled_text(canvas, paint, shaper, &status, Point::new(left + PADDING * font_width / 2.0, top), default_style, font_width, font_height);
+
+        bottom = top - SPACING * font_height;
+    }
+
+    let timeout = Duration::from_millis(SETTINGS.get("message_timeout").read_u16() as u64);
+    let now = Instant::now();
+    let mut next_expiration: Option<Instant> = None;
+
+    let mut panels = Vec::new();
+    if !messages.history.is_empty() {
+        let lines = messages.history.iter()
+            .flat_map(|(_, content)| wrap_styled_text(content, maximum_columns))
+            .collect();
+        panels.push(Panel { lines, accent: border });
+    }
+
+    for notification in messages.notifications.iter() {
+        if let Some(expiration) = notification.expires_at(timeout) {
+            if expiration <= now {
+                continue;
+            }
+            next_expiration = Some(next_expiration.map(|next| next.min(expiration)).unwrap_or(expiration));
+        }
+
+        panels.push(Panel {
+            lines: wrap_styled_text(&notification.content, maximum_columns),
+            accent: kind_color(&notification.kind, &messages.styles, border)
+        });
+    }
+
+    // Newest notifications sit closest to the corner and older ones get pushed upward.
+    for panel in panels.iter().rev() {
+        if bottom <= 0.0 {
+            break;
+        }
+
+        let text_width = panel.lines.iter()
+            .map(|line| styled_text_width(line, font_width))
+            .fold(0.0, f32::max);
+        let width = text_width + PADDING * 2.0 * font_width;
+        let height = (panel.lines.len() as f32 + PADDING) * font_height;
+        let top = bottom - height;
+        let left = right - width;
+        let region = Rect::new(left, top, right, bottom);
+
+        draw_bordered_panel(canvas, paint, region, background, panel.accent);
+
+        paint.set_color(panel.accent);
+        canvas.draw_rect(Rect::new(left, top + font_height / 2.0, left + ACCENT_WIDTH, bottom - font_height / 2.0), &paint);
+
+        let mut line_top = top + PADDING * font_height / 2.0;
+        for line in panel.lines.iter() {
+            draw_styled_text(canvas, paint, shaper, line, Point::new(left + PADDING * font_width, line_top), default_style, font_width, font_height);
+            line_top += font_height;
+        }
+
+        bottom = top - SPACING * font_height;
+    }
+
+    if let Some(next_expiration) = next_expiration {
+        REDRAW_SCHEDULER.schedule(next_expiration);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use skulpin::skia_safe::Color4f;
+
+    use crate::editor::Colors;
+    use super::*;
+
+    fn style(foreground: Option<Color4f>, background: Option<Color4f>) -> Option<Arc<Style>> {
+        Some(Arc::new(Style::new(Colors::new(foreground, background, None))))
+    }
+
+    fn styles(error: Option<Arc<Style>>) -> MessageStyles {
+        MessageStyles { error, warning: None, search: None }
+    }
+
+    #[test]
+    fn accents_come_from_the_highlight_groups() {
+        let error = style(Some(Color4f::new(0.0, 1.0, 0.0, 1.0)), None);
+        assert_eq!(kind_color(&MessageKind::Error, &styles(error), Color::WHITE), Color::GREEN);
+        let error = style(Some(Color4f::new(1.0, 1.0, 1.0, 1.0)), Some(Color4f::new(0.0, 0.0, 1.0, 1.0)));
+        assert_eq!(kind_color(&MessageKind::EchoError, &styles(error), Color::WHITE), Color::BLUE);
+    }
+
+    #[test]
+    fn fixed_colors_are_used_without_highlight_groups() {
+        assert_eq!(kind_color(&MessageKind::Error, &styles(None), Color::WHITE), Color::from_rgb(224, 82, 82));
+        assert_eq!(kind_color(&MessageKind::Warning, &styles(None), Color::WHITE), Color::from_rgb(229, 192, 90));
+        assert_eq!(kind_color(&MessageKind::SearchCount, &styles(None), Color::WHITE), Color::from_rgb(97, 175, 239));
+        assert_eq!(kind_color(&MessageKind::Echo, &styles(None), Color::WHITE), Color::WHITE);
+    }
+
+    #[test]
+    fn lua_errors_stand_apart_from_other_errors() {
+        for error in vec![None, style(Some(Color4f::new(1.0, 0.0, 0.0, 1.0)), None)] {
+            let styles = styles(error);
+            let lua_error = kind_color(&MessageKind::LuaError, &styles, Color::WHITE);
+            assert_ne!(lua_error, kind_color(&MessageKind::Error, &styles, Color::WHITE));
+            assert_ne!(lua_error, Color::from_rgb(198, 120, 221));
+        }
+    }
+}
@@ -1,31 +1,59 @@
+use std::collections::HashMap;
 use std::sync::Arc;
 
 use skulpin::CoordinateSystemHelper;
-use skulpin::skia_safe::{Canvas, Paint, Surface, Budgeted, Rect, colors, dash_path_effect};
+use rmpv::Value;
+use skulpin::skia_safe::{Canvas, Paint, Point, Surface, Budgeted, Rect, Color, BlendMode, colors, dash_path_effect};
 use skulpin::skia_safe::gpu::SurfaceOrigin;
 use log::trace;
 
 mod caching_shaper;
 mod cursor_renderer;
+mod cursor_vfx;
+mod command_line_renderer;
+mod message_renderer;
+mod popup_menu_renderer;
+mod tabline_renderer;
+mod layers;
+mod scroll_animation;
+mod animation_utils;
+mod overlay;
+mod visual_bell;
 
 pub use caching_shaper::CachingShaper;
 
+use caching_shaper::DEFAULT_FONT_SIZE;
 use cursor_renderer::CursorRenderer;
-use crate::editor::{EDITOR, Style};
+use scroll_animation::ScrollAnimator;
+use layers::FloatingEffects;
+use visual_bell::VisualBell;
+use tabline_renderer::TablineRenderer;
+use crate::editor::{DEFAULT_GRID, RenderFrame, Style, WindowFrame, WindowKind};
+use crate::bridge::BRIDGE;
 
 pub struct Renderer {
-    surface: Option<Surface>,
+    surfaces: HashMap<u64, Surface>,
+    window_regions: Vec<(u64, Rect)>,
     paint: Paint,
     shaper: CachingShaper,
 
     pub font_width: f32,
     pub font_height: f32,
+    pub tabline_height: f32,
+    // Where the cursor was drawn during the last frame, for positioning input method popups.
+    pub cursor_region: Rect,
+    preedit: String,
+    // Zoom applied on top of the size guifont asks for.
+    font_scale: f32,
     cursor_renderer: CursorRenderer,
+    tabline_renderer: TablineRenderer,
+    scroll_animator: ScrollAnimator,
+    visual_bell: VisualBell,
 }
 
 impl Renderer {
     pub fn new() -> Renderer {
-        let surface = None;
+        let surfaces = HashMap::new();
         let mut paint = Paint::new(colors::WHITE, None);
         paint.set_anti_alias(false);
         
@@ -33,8 +61,16 @@ impl Renderer {
 
         let (font_width, font_height) = shaper.font_base_dimensions();
         let cursor_renderer = CursorRenderer::new();
-
-        Renderer { surface, paint, shaper, font_width, font_height, cursor_renderer }
+        let tabline_renderer = TablineRenderer::new();
+        let scroll_animator = ScrollAnimator::new();
+        let visual_bell = VisualBell::new();
+
+        Renderer { 
+            surfaces, window_regions: Vec::new(), paint, shaper, 
+            font_width, font_height, tabline_height: 0.0, 
+            cursor_region: Rect::new_empty(), preedit: String::new(), font_scale: 1.0,
+            cursor_renderer, tabline_renderer, scroll_animator, visual_bell
+        }
     }
 
     fn set_font(&mut self, name: Option<&str>, size: Option<f32>) {
@@ -44,6 +80,67 @@ impl Renderer {
         self.font_height = font_height;
     }
 
+    // Switches to the font neovim asked for through guifont scaled by the current zoom,
+    // returning true if it differs from the current one.
+    fn update_font(&mut self, font_name: &Option<String>, font_size: Option<f32>) -> bool {
+        let font_size = font_size.unwrap_or(DEFAULT_FONT_SIZE) * self.font_scale;
+        let font_changed =
+            font_name != &self.shaper.font_name ||
+            (font_size - self.shaper.base_size).abs() > std::f32::EPSILON;
+        if font_changed {
+            self.set_font(font_name.as_deref(), Some(font_size));
+        }
+        font_changed
+    }
+
+    // Zooms the font without changing guifont. Takes effect on the next frame.
+    pub fn set_font_scale(&mut self, font_scale: f32) {
+        self.font_scale = font_scale;
+    }
+
+    // Sets the text an input method is composing, which is drawn over the cursor until it is
+    // either typed or cancelled.
+    pub fn set_preedit(&mut self, preedit: String) {
+        self.preedit = preedit;
+    }
+
+    // Finds the tab under a point in window coordinates, if the tabline is showing.
+    pub fn tab_at(&self, position: Point) -> Option<Value> {
+        self.tabline_renderer.tab_at(position)
+    }
+
+    fn cell_in_region(&self, region: &Rect, position: Point) -> (u32, u32) {
+        (
+            ((position.x - region.left) / self.font_width).max(0.0) as u32,
+            ((position.y - region.top) / self.font_height).max(0.0) as u32
+        )
+    }
+
+    // Finds the top most grid under a point in window coordinates along with the cell under the
+    // point within that grid.
+    pub fn grid_at(&self, position: Point) -> (u64, (u32, u32)) {
+        self.window_regions.iter().rev()
+            .find(|(_, region)| {
+                position.x >= region.left && position.x < region.right &&
+                position.y >= region.top && position.y < region.bottom
+            })
+            .map(|(grid_id, region)| (*grid_id, self.cell_in_region(region, position)))
+            .unwrap_or_else(|| {
+                let default_region = Rect::new(0.0, self.tabline_height, 0.0, 0.0);
+                (DEFAULT_GRID, self.cell_in_region(&default_region, position))
+            })
+    }
+
+    // Finds the cell under a point relative to a specific grid, even if the point is outside of
+    // it. Drags have to be reported against the grid they started in.
+    pub fn grid_position(&self, grid_id: u64, position: Point) -> (u32, u32) {
+        let region = self.window_regions.iter()
+            .find(|(region_grid_id, _)| *region_grid_id == grid_id)
+            .map(|(_, region)| *region)
+            .unwrap_or_else(|| Rect::new(0.0, self.tabline_height, 0.0, 0.0));
+        self.cell_in_region(&region, position)
+    }
+
     fn compute_text_region(&self, grid_pos: (u64, u64), cell_width: u64) -> Rect {
         let (grid_x, grid_y) = grid_pos;
         let x = grid_x as f32 * self.font_width;
@@ -53,12 +150,20 @@ impl Renderer {
         Rect::new(x, y, x + width, y + height)
     }
 
-    fn draw_background(&mut self, canvas: &mut Canvas, grid_pos: (u64, u64), cell_width:u64, style: &Option<Arc<Style>>, default_style: &Arc<Style>) {
+    // Only floats are composited over other windows by us. Everywhere else neovim has already
+    // blended winblend and pumblend into the colors it sent.
+    fn draw_background(&mut self, canvas: &mut Canvas, grid_pos: (u64, u64), cell_width:u64, style: &Option<Arc<Style>>, default_style: &Arc<Style>, translucent: bool) {
         let region = self.compute_text_region(grid_pos, cell_width);
         let style = style.as_ref().unwrap_or(default_style);
 
-        self.paint.set_color(style.background(&default_style.colors).to_color());
+        // Backgrounds replace whatever was there before rather than blending with it so that
+        // partially transparent cells don't build up over repeated redraws.
+        let color = style.background(&default_style.colors).to_color();
+        let alpha = if translucent { style.background_alpha() } else { 255 };
+        self.paint.set_color(color.with_a(alpha));
+        self.paint.set_blend_mode(BlendMode::Src);
         canvas.draw_rect(region, &self.paint);
+        self.paint.set_blend_mode(BlendMode::SrcOver);
     }
 
     fn draw_foreground(&mut self, canvas: &mut Canvas, text: &str, grid_pos: (u64, u64), cell_width: u64, style: &Option<Arc<Style>>, default_style: &Arc<Style>) {
@@ -107,64 +212,206 @@ impl Renderer {
         canvas.restore();
     }
 
-    pub fn draw(&mut self, gpu_canvas: &mut Canvas, coordinate_system_helper: &CoordinateSystemHelper) -> bool {
-        trace!("Rendering");
-        let ((draw_commands, should_clear), default_style, cursor, font_name, font_size) = {
-            let mut editor = EDITOR.lock();
-            (
-                editor.build_draw_commands(), 
-                editor.default_style.clone(), 
-                editor.cursor.clone(),
-                editor.font_name.clone(),
-                editor.font_size
-            )
-        };
-
-        let font_changed = 
-            font_name != self.shaper.font_name || 
-            font_size.map(|new_size| (new_size - self.shaper.base_size).abs() > std::f32::EPSILON).unwrap_or(false);
-        if font_changed {
-            self.set_font(font_name.as_deref(), font_size);
+    // Applies a window's new draw commands to its surface. Each grid is drawn into a surface of its
+    // own so that windows can be composited independently of each other.
+    fn draw_window_surface(&mut self, gpu_canvas: &mut Canvas, window: &WindowFrame, default_style: &Arc<Style>, scale: f32) {
+        let (width, height) = window.size;
+        let pixel_width = (width as f32 * self.font_width * scale).ceil() as i32;
+        let pixel_height = (height as f32 * self.font_height * scale).ceil() as i32;
+
+        if pixel_width <= 0 || pixel_height <= 0 {
+            self.surfaces.remove(&window.grid_id);
+            self.scroll_animator.clear(window.grid_id);
+            return;
         }
 
-        if should_clear {
-            self.surface = None;
-        }
-
-        let mut surface = self.surface.take().unwrap_or_else(|| {
+        let existing_surface = self.surfaces.remove(&window.grid_id)
+            .filter(|surface| !window.should_clear && surface.width() == pixel_width && surface.height() == pixel_height);
+        let mut surface = if let Some(mut surface) = existing_surface {
+            if !window.scrolls.is_empty() {
+                self.scroll_animator.start(window.grid_id, &window.scrolls, &surface.image_snapshot(), self.font_width, self.font_height, scale);
+            }
+            surface
+        } else {
+            self.scroll_animator.clear(window.grid_id);
             let mut context = gpu_canvas.gpu_context().unwrap();
             let budgeted = Budgeted::YES;
-            let image_info = gpu_canvas.image_info();
+            let image_info = gpu_canvas.image_info().with_dimensions((pixel_width, pixel_height));
             let surface_origin = SurfaceOrigin::TopLeft;
             let mut surface = Surface::new_render_target(&mut context, budgeted, &image_info, None, surface_origin, None, None).expect("Could not create surface");
             let canvas = surface.canvas();
-            canvas.clear(default_style.colors.background.clone().unwrap().to_color());
+            // Floats start out transparent so that blended cells show the windows behind them.
+            if window.kind == WindowKind::Floating {
+                canvas.clear(Color::TRANSPARENT);
+            } else {
+                canvas.clear(default_style.colors.background.clone().unwrap().to_color());
+            }
             surface
-        });
+        };
 
         let mut canvas = surface.canvas();
-        coordinate_system_helper.use_logical_coordinates(&mut canvas);
+        canvas.reset_matrix();
+        canvas.scale((scale, scale));
 
-        for command in draw_commands.iter() {
-            self.draw_background(&mut canvas, command.grid_position.clone(), command.cell_width, &command.style, &default_style);
+        let translucent = window.kind == WindowKind::Floating;
+        for command in window.draw_commands.iter() {
+            self.draw_background(&mut canvas, command.grid_position.clone(), command.cell_width, &command.style, default_style, translucent);
         }
-        for command in draw_commands.iter() {
-            self.draw_foreground(&mut canvas, &command.text, command.grid_position.clone(), command.cell_width, &command.style, &default_style);
+        for command in window.draw_commands.iter() {
+            self.draw_foreground(&mut canvas, &command.text, command.grid_position.clone(), command.cell_width, &command.style, default_style);
         }
 
-        let image = surface.image_snapshot();
+        self.surfaces.insert(window.grid_id, surface);
+    }
+
+    // Returns true when something changed which affects how many rows and columns fit in the
+    // window.
+    pub fn draw(&mut self, frame: &RenderFrame, focused: bool, gpu_canvas: &mut Canvas, coordinate_system_helper: &CoordinateSystemHelper) -> bool {
+        trace!("Rendering");
+        let RenderFrame { 
+            windows, default_style, 
+            cursor, cursor_grid, cursor_cell, grid_height, 
+            command_line, messages, popup_menu, tabline, font_name, font_size, bell, ..
+        } = frame;
+
+        let font_changed = self.update_font(font_name, *font_size);
+
+        // The grid sits below the tabline, so the grid size has to be recomputed whenever the
+        // tabline appears or disappears.
+        let tabline_height = TablineRenderer::height(&tabline, self.font_height);
+        let tabline_changed = (tabline_height - self.tabline_height).abs() > std::f32::EPSILON;
+        self.tabline_height = tabline_height;
+
         let window_size = coordinate_system_helper.window_logical_size();
-        let image_destination = Rect::new(0.0, 0.0, window_size.width as f32, window_size.height as f32);
-        gpu_canvas.draw_image_rect(image, None, &image_destination, &self.paint);
+        let scale = gpu_canvas.image_info().width() as f32 / window_size.width.max(1) as f32;
+
+        // Surfaces of grids which neovim destroyed are dropped along with them. External windows
+        // are drawn by the renderer of their own OS window.
+        self.surfaces.retain(|grid_id, _| windows.iter()
+            .any(|window| window.grid_id == *grid_id && window.kind != WindowKind::External));
+        let surfaces = &self.surfaces;
+        self.scroll_animator.retain(|grid_id| surfaces.contains_key(&grid_id));
+
+        gpu_canvas.clear(default_style.colors.background.clone().unwrap().to_color());
+        for window in windows.iter().filter(|window| window.kind != WindowKind::External) {
+            self.draw_window_surface(gpu_canvas, window, &default_style, scale);
+        }
 
-        self.surface = Some(surface);
+        let window_layers = layers::window_layers(windows, self.font_width, self.font_height, tabline_height);
+        let surfaces = &mut self.surfaces;
+        let scroll_animator = &mut self.scroll_animator;
+        let (font_width, font_height) = (self.font_width, self.font_height);
+        layers::draw_layers(gpu_canvas, &window_layers, &FloatingEffects::from_settings(), |canvas, layer| {
+            if let Some(surface) = surfaces.get_mut(&layer.grid_id) {
+                scroll_animator.draw_window(
+                    canvas, layer.grid_id, &surface.image_snapshot(), layer.destination, font_width, font_height, scale);
+            }
+        });
+        self.window_regions = window_layers.into_iter().map(|layer| (layer.grid_id, layer.destination)).collect();
+
+        self.cursor_renderer.command_line_in_grid = !command_line.external;
+        let cursor_is_external = windows.iter()
+            .any(|window| window.grid_id == *cursor_grid && window.kind == WindowKind::External);
+
+        // The external command line draws its own cursor, so the grid cursor is hidden while it
+        // is open.
+        let command_line_origin = if command_line.is_visible() {
+            command_line_renderer::draw_command_line(
+                &command_line, &default_style, gpu_canvas,
+                &mut self.paint, &mut self.shaper,
+                self.font_width, self.font_height,
+                window_size.width as f32, window_size.height as f32)
+        } else {
+            if !cursor_is_external {
+                gpu_canvas.save();
+                gpu_canvas.translate((0.0, tabline_height));
+                self.cursor_renderer.draw(
+                    cursor.clone(), &cursor_cell, *grid_height, &default_style.colors, focused,
+                    self.font_width, self.font_height, 
+                    &mut self.paint, &mut self.shaper,
+                    gpu_canvas);
+                gpu_canvas.restore();
+
+                let (cursor_x, cursor_y) = cursor.position;
+                self.cursor_region = Rect::from_xywh(
+                    cursor_x as f32 * self.font_width, cursor_y as f32 * self.font_height + tabline_height,
+                    self.font_width, self.font_height);
+
+                if !self.preedit.is_empty() {
+                    overlay::draw_preedit(
+                        &self.preedit, Point::new(self.cursor_region.left, self.cursor_region.top), &default_style,
+                        gpu_canvas, &mut self.paint, &mut self.shaper,
+                        self.font_width, self.font_height);
+                }
+            }
+            None
+        };
 
-        self.cursor_renderer.draw(
-            cursor, &default_style.colors, 
-            self.font_width, self.font_height, 
+        self.tabline_renderer.draw(
+            &tabline, &default_style, gpu_canvas,
             &mut self.paint, &mut self.shaper,
-            gpu_canvas);
+            self.font_width, self.font_height,
+            window_size.width as f32);
 
-        font_changed
+        popup_menu_renderer::draw_popup_menu(
+            &popup_menu, &default_style, Point::new(0.0, tabline_height), command_line_origin, gpu_canvas,
+            &mut self.paint, &mut self.shaper,
+            self.font_width, self.font_height,
+            window_size.width as f32, window_size.height as f32);
+
+        message_renderer::draw_messages(
+            &messages, &default_style, gpu_canvas,
+            &mut self.paint, &mut self.shaper,
+            self.font_width, self.font_height,
+            window_size.width as f32, window_size.height as f32);
+
+        if *bell {
+            self.visual_bell.ring();
+        }
+        self.visual_bell.draw(gpu_canvas, &mut self.paint, window_size.width as f32, window_size.height as f32);
+
+        overlay::draw_connection_overlay(
+            &BRIDGE.connection_status(), gpu_canvas,
+            &mut self.paint, &mut self.shaper,
+            self.font_width, self.font_height,
+            window_size.width as f32, window_size.height as f32);
+
+        font_changed || tabline_changed
+    }
+
+    // Draws a single external window into an OS window of its own. Only the grid and the cursor
+    // are drawn here since everything else belongs to the main window.
+    pub fn draw_external_window(&mut self, frame: &RenderFrame, grid_id: u64, focused: bool, gpu_canvas: &mut Canvas, coordinate_system_helper: &CoordinateSystemHelper) {
+        self.update_font(&frame.font_name, frame.font_size);
+
+        let window = match frame.windows.iter().find(|window| window.grid_id == grid_id) {
+            Some(window) => window,
+            None => return
+        };
+
+        let window_size = coordinate_system_helper.window_logical_size();
+        let scale = gpu_canvas.image_info().width() as f32 / window_size.width.max(1) as f32;
+
+        gpu_canvas.clear(frame.default_style.colors.background.clone().unwrap().to_color());
+        self.window_regions.clear();
+        self.draw_window_surface(gpu_canvas, window, &frame.default_style, scale);
+
+        if let Some(surface) = self.surfaces.get_mut(&grid_id) {
+            let (width, height) = window.size;
+            let image_destination = Rect::new(0.0, 0.0, width as f32 * self.font_width, height as f32 * self.font_height);
+            self.scroll_animator.draw_window(
+                gpu_canvas, grid_id, &surface.image_snapshot(), image_destination,
+                self.font_width, self.font_height, scale);
+            self.window_regions.push((grid_id, image_destination));
+        }
+
+        if frame.cursor_grid == grid_id && !frame.command_line.is_visible() {
+            let (_, height) = window.size;
+            self.cursor_renderer.draw(
+                frame.cursor.clone(), &frame.cursor_cell, height, &frame.default_style.colors, focused,
+                self.font_width, self.font_height,
+                &mut self.paint, &mut self.shaper,
+                gpu_canvas);
+        }
     }
 }
@@ -0,0 +1,188 @@
+use std::sync::Arc;
+
+use skulpin::skia_safe::{Canvas, Paint, PaintStyle, Point, Rect, Color};
+use unicode_segmentation::UnicodeSegmentation;
+
+use crate::bridge::ConnectionStatus;
+use crate::editor::{cell_width, Style, StyledText};
+use crate::settings::SETTINGS;
+use super::CachingShaper;
+
+const PANEL_CORNER_RADIUS: f32 = 6.0;
+const PANEL_PADDING: f32 = 1.0;
+
+pub fn text_width(text: &str, font_width: f32) -> f32 {
+    cell_width(text) as f32 * font_width
+}
+
+pub fn draw_panel(canvas: &mut Canvas, paint: &mut Paint, region: Rect, color: Color) {
+    paint.set_anti_alias(true);
+    paint.set_color(color);
+    canvas.draw_round_rect(region, PANEL_CORNER_RADIUS, PANEL_CORNER_RADIUS, &paint);
+    paint.set_anti_alias(false);
+}
+
+pub fn draw_bordered_panel(canvas: &mut Canvas, paint: &mut Paint, region: Rect, background: Color, border: Color) {
+    draw_panel(canvas, paint, region, background);
+
+    paint.set_anti_alias(true);
+    paint.set_style(PaintStyle::Stroke);
+    paint.set_stroke_width(1.0);
+    paint.set_color(border);
+    canvas.draw_round_rect(region, PANEL_CORNER_RADIUS, PANEL_CORNER_RADIUS, &paint);
+    paint.set_style(PaintStyle::Fill);
+    paint.set_anti_alias(false);
+}
+
+pub fn styled_text_width(content: &StyledText, font_width: f32) -> f32 {
+    content.iter().map(|(_, text)| text_width(text, font_width)).sum()
+}
+
+// Splits highlighted text into lines at newlines and wherever a line would grow past
+// maximum_columns cells.
+pub fn wrap_styled_text(content: &StyledText, maximum_columns: usize) -> Vec<StyledText> {
+    let maximum_columns = maximum_columns.max(1);
+    let mut lines = Vec::new();
+    let mut current_line: StyledText = Vec::new();
+    let mut current_columns = 0;
+
+    for (style, text) in content.iter() {
+        let mut chunk = String::new();
+        for grapheme in text.graphemes(true) {
+            let grapheme_columns = cell_width(grapheme);
+            if grapheme == "\n" || grapheme == "\r\n" ||
+                (current_columns > 0 && current_columns + grapheme_columns > maximum_columns) {
+                if !chunk.is_empty() {
+                    current_line.push((style.clone(), std::mem::take(&mut chunk)));
+                }
+                lines.push(std::mem::take(&mut current_line));
+                current_columns = 0;
+
+                if grapheme == "\n" || grapheme == "\r\n" {
+                    continue;
+                }
+            }
+
+            chunk.push_str(grapheme);
+            current_columns += grapheme_columns;
+        }
+
+        if !chunk.is_empty() {
+            current_line.push((style.clone(), chunk));
+        }
+    }
+
+    if !current_line.is_empty() || lines.is_empty() {
+        lines.push(current_line);
+    }
+
+    lines
+}
+
+// Draws a line of highlighted text with its top left corner at position. Backgrounds are only
+// filled in for chunks which set one so that the panel underneath shows through everywhere else.
+pub fn draw_styled_text(
+        canvas: &mut Canvas, paint: &mut Paint, shaper: &mut CachingShaper, 
+        content: &StyledText, position: Point, default_style: &Arc<Style>,
+        font_width: f32, font_height: f32) {
+    let mut x = position.x;
+    for (style, text) in content.iter() {
+        let width = text_width(text, font_width);
+        let style = style.as_ref().unwrap_or(default_style);
+
+        if style.colors.background.is_some() || style.reverse {
+            paint.set_color(style.background(&default_style.colors).to_color());
+            canvas.draw_rect(Rect::new(x, position.y, x + width, position.y + font_height), &paint);
+        }
+
+        let trimmed_text = text.trim_end();
+        if !trimmed_text.is_empty() {
+            paint.set_color(style.foreground(&default_style.colors).to_color());
+            for blob in shaper.shape_cached(trimmed_text, style.bold, style.italic).iter() {
+                canvas.draw_text_blob(blob, (x, position.y), &paint);
+            }
+        }
+
+        x += width;
+    }
+}
+
+pub fn draw_text(canvas: &mut Canvas, paint: &mut Paint, shaper: &mut CachingShaper, text: &str, position: Point, color: Color) {
+    let text = text.trim_end();
+    if text.is_empty() {
+        return;
+    }
+
+    paint.set_color(color);
+    for blob in shaper.shape_cached(text, false, false).iter() {
+        canvas.draw_text_blob(blob, position, &paint);
+    }
+}
+
+// Draws text an input method is still composing over the cell at position, underlined like
+// terminals do to show that it hasn't been typed yet. The text is measured from its shaped
+// glyphs since composed characters are often double width.
+pub fn draw_preedit(
+        text: &str, position: Point, default_style: &Arc<Style>,
+        canvas: &mut Canvas, paint: &mut Paint, shaper: &mut CachingShaper,
+        font_width: f32, font_height: f32) {
+    let blobs = shaper.shape_cached(text, false, false);
+    let width = blobs.iter()
+        .map(|blob| blob.bounds().right)
+        .fold(font_width, f32::max);
+    let region = Rect::new(position.x, position.y, position.x + width, position.y + font_height);
+
+    paint.set_style(PaintStyle::Fill);
+    paint.set_color(default_style.colors.background.clone().unwrap().to_color());
+    canvas.draw_rect(region, &paint);
+
+    let foreground = default_style.colors.foreground.clone().unwrap().to_color();
+    paint.set_color(foreground);
+    for blob in blobs.iter() {
+        canvas.draw_text_blob(blob, position, &paint);
+    }
+
+    let underline_y = region.bottom - 1.0;
+    paint.set_stroke_width(1.0);
+    paint.set_path_effect(None);
+    canvas.draw_line((region.left, underline_y), (region.right, underline_y), &paint);
+}
+
+// Dims the last frame neovim sent and explains why the connection ended along with how to get it
+// back.
+pub fn draw_connection_overlay(
+        status: &ConnectionStatus, canvas: &mut Canvas, 
+        paint: &mut Paint, shaper: &mut CachingShaper,
+        font_width: f32, font_height: f32, 
+        window_width: f32, window_height: f32) {
+    let reason = match status {
+        ConnectionStatus::Disconnected(reason) => reason,
+        _ => return
+    };
+
+    let action = match &SETTINGS.server_address {
+        Some(address) => format!("Press Enter to reattach to {} or q to quit", address),
+        None => "Press Enter to restart neovim or q to quit".to_string()
+    };
+    let lines = [reason.as_str(), "", action.as_str()];
+
+    paint.set_color(Color::from_argb(160, 0, 0, 0));
+    canvas.draw_rect(Rect::new(0.0, 0.0, window_width, window_height), &paint);
+
+    let text_width = lines.iter()
+        .map(|line| text_width(line, font_width))
+        .fold(0.0, f32::max);
+    let panel_width = text_width + PANEL_PADDING * 2.0 * font_width;
+    let panel_height = (lines.len() as f32 + PANEL_PADDING * 2.0) * font_height;
+    let left = ((window_width - panel_width) / 2.0).max(0.0);
+    let top = ((window_height - panel_height) / 2.0).max(0.0);
+
+    draw_panel(canvas, paint, Rect::new(left, top, left + panel_width, top + panel_height), Color::from_rgb(40, 40, 40));
+
+    for (index, line) in lines.iter().enumerate() {
+        let position = Point::new(
+            left + PANEL_PADDING * font_width, 
+            top + (index as f32 + PANEL_PADDING) * font_height);
+        draw_text(canvas, paint, shaper, line, position, Color::from_rgb(230, 230, 230));
+    }
+}
@@ -0,0 +1,141 @@
+use std::sync::Arc;
+
+use skulpin::skia_safe::{Canvas, Paint, Point, Rect, Color};
+
+use crate::editor::{PopupMenu, Style};
+use super::CachingShaper;
+use super::overlay::{draw_panel, text_width};
+
+const MAXIMUM_VISIBLE_ITEMS: usize = 15;
+const COLUMN_SPACING: f32 = 1.0;
+const PADDING: f32 = 1.0;
+const SCROLLBAR_WIDTH: f32 = 0.5;
+
+fn blend_color(color: Color, blend: u64) -> Color {
+    let alpha = (255 * (100 - blend.min(100)) / 100) as u8;
+    Color::from_argb(alpha, color.r(), color.g(), color.b())
+}
+
+fn column_width<'a>(texts: impl Iterator<Item = &'a String>, font_width: f32) -> f32 {
+    texts.map(|text| text_width(text, font_width)).fold(0.0, f32::max)
+}
+
+fn draw_item_text(
+        canvas: &mut Canvas, paint: &mut Paint, shaper: &mut CachingShaper,
+        text: &str, position: Point, style: &Arc<Style>, default_style: &Arc<Style>) {
+    let text = text.trim_end();
+    if text.is_empty() {
+        return;
+    }
+
+    paint.set_color(style.foreground(&default_style.colors).to_color());
+    for blob in shaper.shape_cached(text, style.bold, style.italic).iter() {
+        canvas.draw_text_blob(blob, position, &paint);
+    }
+}
+
+// Draws the completion menu as a floating list anchored under the cell neovim asked for. When
+// there isn't enough room below the anchor the menu flips above it instead. Item backgrounds
+// are made translucent according to pumblend.
+pub fn draw_popup_menu(
+        popup_menu: &PopupMenu, default_style: &Arc<Style>,
+        grid_origin: Point, command_line_origin: Option<Point>,
+        canvas: &mut Canvas, paint: &mut Paint, shaper: &mut CachingShaper,
+        font_width: f32, font_height: f32,
+        window_width: f32, window_height: f32) {
+    if !popup_menu.visible || popup_menu.items.is_empty() {
+        return;
+    }
+
+    let (anchor_column, anchor_row) = popup_menu.anchor;
+    let (anchor_left, anchor_top) = if popup_menu.is_command_line_menu() {
+        match command_line_origin {
+            Some(origin) => (origin.x + anchor_column as f32 * font_width, origin.y),
+            None => return
+        }
+    } else {
+        (grid_origin.x + anchor_column as f32 * font_width, grid_origin.y + anchor_row as f32 * font_height)
+    };
+
+    let normal_style = popup_menu.styles.normal.clone().unwrap_or_else(|| default_style.clone());
+    let selected_style = popup_menu.styles.selected.clone().unwrap_or_else(|| normal_style.clone());
+    let scrollbar_style = popup_menu.styles.scrollbar.clone().unwrap_or_else(|| normal_style.clone());
+    let thumb_style = popup_menu.styles.thumb.clone().unwrap_or_else(|| selected_style.clone());
+
+    // Prefer opening downward, but flip above the anchor if that shows more items.
+    let rows_below = ((window_height - anchor_top) / font_height - 1.0).max(0.0) as usize;
+    let rows_above = (anchor_top / font_height).max(0.0) as usize;
+    let wanted_rows = popup_menu.items.len().min(MAXIMUM_VISIBLE_ITEMS);
+    let (visible_rows, opens_downward) = if rows_below >= wanted_rows || rows_below >= rows_above {
+        (wanted_rows.min(rows_below), true)
+    } else {
+        (wanted_rows.min(rows_above), false)
+    };
+
+    if visible_rows == 0 {
+        return;
+    }
+
+    // Keep the selected item in view by scrolling just far enough to reach it.
+    let scroll_offset = match popup_menu.selected {
+        Some(selected) if selected as usize >= visible_rows => selected as usize + 1 - visible_rows,
+        _ => 0
+    };
+
+    let items = &popup_menu.items;
+    let word_width = column_width(items.iter().map(|item| &item.word), font_width);
+    let kind_width = column_width(items.iter().map(|item| &item.kind), font_width);
+    let menu_width = column_width(items.iter().map(|item| &item.menu), font_width);
+
+    let spacing = COLUMN_SPACING * font_width;
+    let kind_left = PADDING * font_width + word_width + if kind_width > 0.0 { spacing } else { 0.0 };
+    let menu_left = kind_left + kind_width + if menu_width > 0.0 { spacing } else { 0.0 };
+    let has_scrollbar = popup_menu.items.len() > visible_rows;
+    let scrollbar_width = if has_scrollbar { SCROLLBAR_WIDTH * font_width } else { 0.0 };
+
+    let width = (menu_left + menu_width + PADDING * font_width + scrollbar_width).min(window_width);
+    let height = visible_rows as f32 * font_height;
+    let left = anchor_left.min(window_width - width).max(0.0);
+    let top = if opens_downward {
+        anchor_top + font_height
+    } else {
+        anchor_top - height
+    };
+    let region = Rect::new(left, top, left + width, top + height);
+
+    let background = normal_style.background(&default_style.colors).to_color();
+    draw_panel(canvas, paint, region, blend_color(background, popup_menu.blend));
+
+    canvas.save();
+    canvas.clip_rect(region, None, Some(false));
+
+    for (row, (index, item)) in popup_menu.items.iter().enumerate().skip(scroll_offset).take(visible_rows).enumerate() {
+        let row_top = top + row as f32 * font_height;
+        let style = if popup_menu.selected == Some(index as u64) {
+            let selected_background = selected_style.background(&default_style.colors).to_color();
+            paint.set_color(blend_color(selected_background, popup_menu.blend));
+            canvas.draw_rect(Rect::new(left, row_top, left + width - scrollbar_width, row_top + font_height), &paint);
+            &selected_style
+        } else {
+            &normal_style
+        };
+
+        draw_item_text(canvas, paint, shaper, &item.word, Point::new(left + PADDING * font_width, row_top), style, default_style);
+        draw_item_text(canvas, paint, shaper, &item.kind, Point::new(left + kind_left, row_top), style, default_style);
+        draw_item_text(canvas, paint, shaper, &item.menu, Point::new(left + menu_left, row_top), style, default_style);
+    }
+
+    if has_scrollbar {
+        let scrollbar_left = left + width - scrollbar_width;
+        paint.set_color(scrollbar_style.background(&default_style.colors).to_color());
+        canvas.draw_rect(Rect::new(scrollbar_left, top, left + width, top + height), &paint);
+
+        let item_count = popup_menu.items.len() as f32;
+        let thumb_top = top + height * scroll_offset as f32 / item_count;
+        let thumb_height = (height * visible_rows as f32 / item_count).max(font_height / 2.0);
+        paint.set_color(thumb_style.background(&default_style.colors).to_color());
+        canvas.draw_rect(Rect::new(scrollbar_left, thumb_top, left + width, thumb_top + thumb_height), &paint);
+    }
+
+    canvas.restore();
+}
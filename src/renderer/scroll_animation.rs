@@ -0,0 +1,157 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use skulpin::skia_safe::{Canvas, Image, IRect, Paint, Rect, ClipOp};
+use skulpin::skia_safe::canvas::SrcRectConstraint;
+
+use crate::editor::GridScroll;
+use crate::redraw_scheduler::REDRAW_SCHEDULER;
+use crate::settings::SETTINGS;
+use super::animation_utils::ease_out_cubic;
+
+// A scrolled region sliding from where its contents were to where neovim put them.
+struct ScrollAnimation {
+    // Top, bottom, left and right of the region in cells.
+    region: (u64, u64, u64, u64),
+    // What the region showed before the scroll. It trails behind the new contents so that rows
+    // which are still sliding out stay visible.
+    snapshot: Option<Image>,
+    // Distance in pixels between the old contents and the new contents.
+    scroll_distance: f32,
+    start_offset: f32,
+    start_time: Instant
+}
+
+impl ScrollAnimation {
+    fn progress(&self, now: Instant, length: Duration) -> f32 {
+        if length.as_secs_f32() <= 0.0 {
+            1.0
+        } else {
+            (now.duration_since(self.start_time).as_secs_f32() / length.as_secs_f32()).min(1.0)
+        }
+    }
+
+    fn offset(&self, now: Instant, length: Duration) -> f32 {
+        self.start_offset * (1.0 - ease_out_cubic(self.progress(now, length)))
+    }
+}
+
+fn animation_length() -> Duration {
+    Duration::from_millis(SETTINGS.get("scroll_animation_length").read_u16() as u64)
+}
+
+// Tracks the scroll animations of every grid. Only the regions neovim scrolled move, so splits
+// sharing a grid with them stay put.
+pub struct ScrollAnimator {
+    animations: HashMap<u64, Vec<ScrollAnimation>>
+}
+
+impl ScrollAnimator {
+    pub fn new() -> ScrollAnimator {
+        ScrollAnimator { animations: HashMap::new() }
+    }
+
+    // Starts animating the given scrolls. Must be called before the draw commands which came with
+    // the scrolls are applied, since the surface image is what the regions showed before.
+    pub fn start(&mut self, grid_id: u64, scrolls: &[GridScroll], surface_image: &Image, font_width: f32, font_height: f32, scale: f32) {
+        if !SETTINGS.get("scroll_animation").read_bool() {
+            self.animations.remove(&grid_id);
+            return;
+        }
+
+        let now = Instant::now();
+        let length = animation_length();
+        let animations = self.animations.entry(grid_id).or_insert_with(Vec::new);
+        animations.retain(|animation| animation.progress(now, length) < 1.0);
+
+        for scroll in scrolls {
+            let region = (scroll.top, scroll.bottom, scroll.left, scroll.right);
+
+            // Scrolling again before the last animation finished continues from wherever the
+            // region currently is instead of jumping.
+            let remaining_offset = animations.iter()
+                .position(|animation| animation.region == region)
+                .map(|index| animations.remove(index).offset(now, length))
+                .unwrap_or(0.0);
+
+            let pixel_region = IRect::new(
+                (scroll.left as f32 * font_width * scale) as i32,
+                (scroll.top as f32 * font_height * scale) as i32,
+                (scroll.right as f32 * font_width * scale).ceil() as i32,
+                (scroll.bottom as f32 * font_height * scale).ceil() as i32);
+            let scroll_distance = scroll.rows as f32 * font_height;
+
+            animations.push(ScrollAnimation {
+                region,
+                snapshot: surface_image.new_subset(pixel_region),
+                scroll_distance,
+                start_offset: remaining_offset + scroll_distance,
+                start_time: now
+            });
+        }
+    }
+
+    // Drops the animations of a grid whose surface was recreated, since there is nothing left to
+    // animate from.
+    pub fn clear(&mut self, grid_id: u64) {
+        self.animations.remove(&grid_id);
+    }
+
+    pub fn retain<F: FnMut(u64) -> bool>(&mut self, mut keep: F) {
+        self.animations.retain(|grid_id, _| keep(*grid_id));
+    }
+
+    // Draws a window's image at its destination with the regions which are still animating slid
+    // into place. Rows which were newly exposed are part of the image, so they slide in along
+    // with the rest of the region.
+    pub fn draw_window(&mut self,
+            canvas: &mut Canvas, grid_id: u64, image: &Image, destination: Rect,
+            font_width: f32, font_height: f32, scale: f32) {
+        let paint = Paint::default();
+        let now = Instant::now();
+        let length = animation_length();
+
+        let animations = match self.animations.get_mut(&grid_id) {
+            Some(animations) => animations,
+            None => {
+                canvas.draw_image_rect(image, None, &destination, &paint);
+                return;
+            }
+        };
+        animations.retain(|animation| animation.progress(now, length) < 1.0);
+
+        let region_rect = |(top, bottom, left, right): (u64, u64, u64, u64), scale: f32, origin: (f32, f32)| {
+            let (origin_x, origin_y) = origin;
+            Rect::new(
+                origin_x + left as f32 * font_width * scale, origin_y + top as f32 * font_height * scale,
+                origin_x + right as f32 * font_width * scale, origin_y + bottom as f32 * font_height * scale)
+        };
+
+        canvas.save();
+        for animation in animations.iter() {
+            canvas.clip_rect(region_rect(animation.region, 1.0, (destination.left, destination.top)), ClipOp::Difference, Some(false));
+        }
+        canvas.draw_image_rect(image, None, &destination, &paint);
+        canvas.restore();
+
+        for animation in animations.iter() {
+            let offset = animation.offset(now, length);
+            let region = region_rect(animation.region, 1.0, (destination.left, destination.top));
+            let source = region_rect(animation.region, scale, (0.0, 0.0));
+
+            canvas.save();
+            canvas.clip_rect(region, None, Some(false));
+            canvas.draw_image_rect(image, Some((&source, SrcRectConstraint::Strict)), &region.with_offset((0.0, offset)), &paint);
+            if let Some(snapshot) = &animation.snapshot {
+                canvas.draw_image_rect(snapshot, None, &region.with_offset((0.0, offset - animation.scroll_distance)), &paint);
+            }
+            canvas.restore();
+        }
+
+        if animations.is_empty() {
+            self.animations.remove(&grid_id);
+        } else {
+            REDRAW_SCHEDULER.queue_next_frame();
+        }
+    }
+}
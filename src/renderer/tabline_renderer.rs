@@ -0,0 +1,118 @@
+use std::sync::Arc;
+
+use rmpv::Value;
+use skulpin::skia_safe::{Canvas, Paint, Point, Rect};
+use unicode_segmentation::UnicodeSegmentation;
+
+use crate::editor::{Style, Tabline};
+use super::CachingShaper;
+use super::overlay::{draw_panel, draw_text, text_width};
+
+const TABLINE_HEIGHT: f32 = 1.5;
+const MAXIMUM_TAB_WIDTH: f32 = 30.0;
+const PADDING: f32 = 1.0;
+const TAB_SPACING: f32 = 0.25;
+
+// Tab names are the full path of the buffer in the tab's current window, which is mostly noise in
+// a tab strip, so only the file name is shown.
+fn tab_title(name: &str) -> &str {
+    let file_name = name.rsplit(|character| character == '/' || character == '\\').next().unwrap_or(name);
+    if file_name.is_empty() {
+        "[No Name]"
+    } else {
+        file_name
+    }
+}
+
+// Cuts text down to fit within maximum_columns cells, ending it with an ellipsis when anything
+// had to be removed.
+fn truncate_title(title: &str, maximum_columns: usize) -> String {
+    if title.graphemes(true).count() <= maximum_columns {
+        title.to_string()
+    } else {
+        let mut truncated: String = title.graphemes(true).take(maximum_columns.saturating_sub(1)).collect();
+        truncated.push('…');
+        truncated
+    }
+}
+
+pub struct TablineRenderer {
+    tab_regions: Vec<(Rect, Value)>
+}
+
+impl TablineRenderer {
+    pub fn new() -> TablineRenderer {
+        TablineRenderer { tab_regions: Vec::new() }
+    }
+
+    pub fn height(tabline: &Tabline, font_height: f32) -> f32 {
+        if tabline.is_visible() {
+            (font_height * TABLINE_HEIGHT).round()
+        } else {
+            0.0
+        }
+    }
+
+    // Finds the tab drawn under the given point during the last frame.
+    pub fn tab_at(&self, position: Point) -> Option<Value> {
+        self.tab_regions.iter()
+            .find(|(region, _)| {
+                position.x >= region.left && position.x < region.right &&
+                position.y >= region.top && position.y < region.bottom
+            })
+            .map(|(_, tab)| tab.clone())
+    }
+
+    // Draws the tab strip across the top of the window. Tabs share the available width evenly
+    // rather than being cut off at the end of the strip, so every tab stays reachable no matter
+    // how many are open.
+    pub fn draw(&mut self,
+            tabline: &Tabline, default_style: &Arc<Style>,
+            canvas: &mut Canvas, paint: &mut Paint, shaper: &mut CachingShaper,
+            font_width: f32, font_height: f32, window_width: f32) {
+        self.tab_regions.clear();
+
+        let height = TablineRenderer::height(tabline, font_height);
+        if height == 0.0 {
+            return;
+        }
+
+        let fill_style = tabline.styles.fill.clone().unwrap_or_else(|| default_style.clone());
+        let tab_style = tabline.styles.tab.clone().unwrap_or_else(|| fill_style.clone());
+        let selected_style = tabline.styles.selected.clone().unwrap_or_else(|| default_style.clone());
+
+        paint.set_color(fill_style.background(&default_style.colors).to_color());
+        canvas.draw_rect(Rect::new(0.0, 0.0, window_width, height), &paint);
+
+        let spacing = TAB_SPACING * font_width;
+        let tab_count = tabline.tabs.len() as f32;
+        let available_width = (window_width - spacing * (tab_count + 1.0)) / tab_count;
+        let tab_width = available_width
+            .min(MAXIMUM_TAB_WIDTH * font_width)
+            .max(font_width);
+        let maximum_columns = ((tab_width / font_width) - PADDING * 2.0).max(1.0) as usize;
+        let text_top = (height - font_height) / 2.0;
+
+        let mut left = spacing;
+        for tab in tabline.tabs.iter() {
+            let style = if tabline.is_current(tab) {
+                &selected_style
+            } else {
+                &tab_style
+            };
+
+            let region = Rect::new(left, text_top / 2.0, left + tab_width, height);
+            draw_panel(canvas, paint, region, style.background(&default_style.colors).to_color());
+
+            let title = truncate_title(tab_title(&tab.name), maximum_columns);
+            let title_left = left + (tab_width - text_width(&title, font_width)) / 2.0;
+            canvas.save();
+            canvas.clip_rect(region, None, Some(false));
+            draw_text(canvas, paint, shaper, &title, Point::new(title_left, text_top), style.foreground(&default_style.colors).to_color());
+            canvas.restore();
+
+            self.tab_regions.push((region, tab.tab.clone()));
+            left += tab_width + spacing;
+        }
+    }
+}
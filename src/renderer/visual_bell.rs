@@ -0,0 +1,60 @@
+use std::time::{Duration, Instant};
+
+use skulpin::skia_safe::{Canvas, Paint, Rect, Color};
+use log::warn;
+
+use crate::redraw_scheduler::REDRAW_SCHEDULER;
+use crate::settings::SETTINGS;
+
+const FLASH_OPACITY: f32 = 0.3;
+
+// Parses colors written as #rrggbb.
+fn parse_color(color: &str) -> Option<Color> {
+    let hex = color.trim().trim_start_matches('#');
+    if hex.len() != 6 {
+        return None;
+    }
+    let packed = u32::from_str_radix(hex, 16).ok()?;
+    Some(Color::from_rgb((packed >> 16) as u8, (packed >> 8) as u8, packed as u8))
+}
+
+// Flashes the whole window when neovim rings the bell, fading out over bell_duration.
+pub struct VisualBell {
+    rung_at: Option<Instant>
+}
+
+impl VisualBell {
+    pub fn new() -> VisualBell {
+        VisualBell { rung_at: None }
+    }
+
+    pub fn ring(&mut self) {
+        self.rung_at = Some(Instant::now());
+    }
+
+    pub fn draw(&mut self, canvas: &mut Canvas, paint: &mut Paint, window_width: f32, window_height: f32) {
+        let rung_at = match self.rung_at {
+            Some(rung_at) => rung_at,
+            None => return
+        };
+
+        let duration = Duration::from_millis(SETTINGS.get("bell_duration").read_u16() as u64);
+        let progress = rung_at.elapsed().as_secs_f32() / duration.as_secs_f32().max(std::f32::EPSILON);
+        if progress >= 1.0 {
+            self.rung_at = None;
+            return;
+        }
+
+        let color_setting = SETTINGS.get("bell_color").read_string();
+        let color = parse_color(&color_setting).unwrap_or_else(|| {
+            warn!("Could not parse bell color {}", color_setting);
+            Color::WHITE
+        });
+
+        let alpha = (255.0 * FLASH_OPACITY * (1.0 - progress)) as u8;
+        paint.set_color(color.with_a(alpha));
+        canvas.draw_rect(Rect::new(0.0, 0.0, window_width, window_height), &paint);
+
+        REDRAW_SCHEDULER.queue_next_frame();
+    }
+}
@@ -3,12 +3,12 @@ use std::convert::TryInto;
 
 use rmpv::Value;
 use nvim_rs::Neovim;
-use nvim_rs::compat::tokio::Compat;
 use flexi_logger::{Logger, Criterion, Naming, Cleanup};
-use tokio::process::ChildStdin;
 use parking_lot::Mutex;
 use log::warn;
 
+use crate::bridge::NeovimWriter;
+use crate::gui_keybindings::default_keybindings;
 use crate::error_handling::ResultPanicExplanation;
 
 lazy_static! {
@@ -19,7 +19,9 @@ lazy_static! {
 pub enum Setting {
     Bool(bool),
     U16(u16),
-    String(String)
+    F32(f32),
+    String(String),
+    Map(HashMap<String, String>)
 }
 
 impl Setting {
@@ -47,6 +49,18 @@ impl Setting {
         }
     }
     
+    fn new_f32(value: f32) -> Setting {
+        Setting::F32(value)
+    }
+
+    pub fn read_f32(&self) -> f32 {
+        if let Setting::F32(value) = self {
+            *value
+        } else {
+            panic!("Could not read setting as f32");
+        }
+    }
+
     fn new_string(value: String) -> Setting {
         Setting::String(value)
     }
@@ -59,6 +73,18 @@ impl Setting {
         }
     }
 
+    fn new_map(value: HashMap<String, String>) -> Setting {
+        Setting::Map(value)
+    }
+
+    pub fn read_map(&self) -> HashMap<String, String> {
+        if let Setting::Map(value) = self {
+            value.clone()
+        } else {
+            panic!("Could not read setting as map");
+        }
+    }
+
     fn parse(&mut self, value: Value) {
         match self {
             Setting::Bool(internal_bool) => {
@@ -73,11 +99,28 @@ impl Setting {
                     *internal_u16 = intermediate as u16;
                 }
             },
+            Setting::F32(internal_f32) => {
+                // Whole numbers come through as integers, so anything numeric is accepted.
+                if let Some(value) = value.as_f64() {
+                    *internal_f32 = value as f32;
+                }
+            },
             Setting::String(internal_string) => {
                 if let Ok(value) = value.try_into() {
                     let intermediate: String = value;
                     *internal_string = intermediate;
                 }
+            },
+            Setting::Map(internal_map) => {
+                // Entries are merged into the current ones so that a dictionary only has to
+                // mention what it changes.
+                if let Some(entries) = value.as_map() {
+                    for (key, value) in entries {
+                        if let (Some(key), Some(value)) = (key.as_str(), value.as_str()) {
+                            internal_map.insert(key.to_string(), value.to_string());
+                        }
+                    }
+                }
             }
         }
     }
@@ -93,7 +136,11 @@ impl Setting {
                 Value::from(value)
             },
             Setting::U16(internal_u16) => Value::from(*internal_u16),
+            Setting::F32(internal_f32) => Value::from(*internal_f32 as f64),
             Setting::String(internal_string) => Value::from(internal_string.as_str()),
+            Setting::Map(internal_map) => Value::Map(internal_map.iter()
+                .map(|(key, value)| (Value::from(key.as_str()), Value::from(value.as_str())))
+                .collect()),
         }
     }
 
@@ -101,18 +148,21 @@ impl Setting {
         match self {
             Setting::Bool(_) => Setting::new_bool(self.read_bool()),
             Setting::U16(_) => Setting::new_u16(self.read_u16()),
+            Setting::F32(_) => Setting::new_f32(self.read_f32()),
             Setting::String(_) => Setting::new_string(self.read_string()),
+            Setting::Map(_) => Setting::new_map(self.read_map()),
         }
     }
 }
 
 pub struct Settings {
     pub neovim_arguments: Vec<String>,
+    pub server_address: Option<String>,
     pub settings: Mutex<HashMap<String, Setting>>
 }
 
 impl Settings {
-    pub async fn read_initial_values(&self, nvim: &Neovim<Compat<ChildStdin>>) {
+    pub async fn read_initial_values<W: NeovimWriter>(&self, nvim: &Neovim<W>) {
         let keys : Vec<String> = self.settings.lock().keys().cloned().collect();
         for name in keys {
             let variable_name = format!("neovide_{}", name.to_string());
@@ -127,12 +177,15 @@ impl Settings {
         }
     }
 
-    pub async fn setup_changed_listeners(&self, nvim: &Neovim<Compat<ChildStdin>>) {
+    pub async fn setup_changed_listeners<W: NeovimWriter>(&self, nvim: &Neovim<W>, channel: u64) {
         let keys : Vec<String> = self.settings.lock().keys().cloned().collect();
         for name in keys {
+            // Reattaching to a server finds the watchers from the last session still in place,
+            // and adding them again would notify every change once per attach.
             let vimscript = 
-                format!("function NeovideNotify{}Changed(d, k, z)\n", name) +
-               &format!("  call rpcnotify(1, \"setting_changed\", \"{}\", g:neovide_{})\n", name, name) +
+                format!("silent! call dictwatcherdel(g:, \"neovide_{}\", \"NeovideNotify{}Changed\")\n", name, name) +
+               &format!("function! NeovideNotify{}Changed(d, k, z)\n", name) +
+               &format!("  call rpcnotify({}, \"setting_changed\", \"{}\", g:neovide_{})\n", channel, name, name) +
                         "endfunction\n" +
                &format!("call dictwatcheradd(g:, \"neovide_{}\", \"NeovideNotify{}Changed\")", name, name);
             nvim.exec(&vimscript, false).await
@@ -160,31 +213,130 @@ impl Settings {
         let mut no_idle = false;
         let mut buffer_frames = 1;
 
-        let neovim_arguments = std::env::args().filter(|arg| {
+        let mut server_address = None;
+
+        let mut arguments = std::env::args();
+        let mut neovim_arguments = Vec::new();
+        while let Some(arg) = arguments.next() {
             if arg == "--log" {
                 Logger::with_str("neovide")
                     .log_to_file()
                     .rotate(Criterion::Size(10_000_000), Naming::Timestamps, Cleanup::KeepLogFiles(1))
                     .start()
                     .expect("Could not start logger");
-                false
             } else if arg == "--noIdle" {
                 no_idle = true;
-                false
             } else if arg == "--extraBufferFrames" {
                 buffer_frames = 60;
-                false
+            } else if arg == "--server" {
+                server_address = arguments.next();
+            } else if arg.starts_with("--server=") {
+                server_address = Some(arg["--server=".len()..].to_string());
             } else {
-                true
+                neovim_arguments.push(arg);
             }
-        }).collect::<Vec<String>>();
+        }
 
         let mut settings = HashMap::new();
 
         settings.insert("no_idle".to_string(),  Setting::new_bool(no_idle));
         settings.insert("extra_buffer_frames".to_string(), Setting::new_u16(buffer_frames));
         settings.insert("refresh_rate".to_string(), Setting::new_u16(60));
+        // Ui extensions are negotiated in ui_attach, so changes to these only apply on the next
+        // connection.
+        settings.insert("ext_cmdline".to_string(), Setting::new_bool(false));
+        settings.insert("ext_messages".to_string(), Setting::new_bool(false));
+        settings.insert("ext_popupmenu".to_string(), Setting::new_bool(false));
+        settings.insert("ext_tabline".to_string(), Setting::new_bool(false));
+        settings.insert("ext_multigrid".to_string(), Setting::new_bool(false));
+        settings.insert("message_timeout".to_string(), Setting::new_u16(4000));
+        // Sizes in pixels for the effects drawn around floating windows. Zero turns an effect off.
+        settings.insert("floating_blur".to_string(), Setting::new_u16(0));
+        settings.insert("floating_shadow".to_string(), Setting::new_u16(8));
+        settings.insert("floating_corner_radius".to_string(), Setting::new_u16(4));
+        settings.insert("scroll_animation".to_string(), Setting::new_bool(false));
+        settings.insert("scroll_animation_length".to_string(), Setting::new_u16(300));
+        // How long the cursor takes to reach a new position in milliseconds, and how much longer
+        // its trailing corners take than its leading ones as a fraction of that.
+        settings.insert("cursor_animation_length".to_string(), Setting::new_u16(130));
+        settings.insert("cursor_trail_size".to_string(), Setting::new_f32(0.7));
+        settings.insert("cursor_blink_fade".to_string(), Setting::new_bool(false));
+        settings.insert("cursor_ligatures".to_string(), Setting::new_bool(false));
+        // The bell flashes the window in this color, written as #rrggbb, for this many
+        // milliseconds.
+        settings.insert("bell_color".to_string(), Setting::new_string("#ffffff".to_string()));
+        settings.insert("bell_duration".to_string(), Setting::new_u16(150));
+        // How files dropped onto the window are opened. One of edit, tabedit, split, vsplit or
+        // argadd.
+        settings.insert("drop_file_action".to_string(), Setting::new_string("edit".to_string()));
+        // Keys handled by Neovide itself, written in key notation and mapped to the name of an
+        // action. One of zoom_in, zoom_out, zoom_reset, toggle_fullscreen, paste, copy or
+        // new_window.
+        settings.insert("keybindings".to_string(), Setting::new_map(default_keybindings()));
+        // Older name for a paste binding, kept working for existing configs. Empty adds nothing.
+        settings.insert("paste_shortcut".to_string(), Setting::new_string(String::new()));
+        // Whether Alt held with a character key sends it to neovim as Meta. When off, Alt types
+        // whatever character the keyboard layout gives it, as is usual on macOS.
+        settings.insert("alt_is_meta".to_string(), Setting::new_bool(!cfg!(target_os = "macos")));
+        // Particles emitted as the cursor moves. The mode is one of railgun, torpedo or pixiedust,
+        // and anything else turns them off. Lifetime is in seconds, density in particles per cell
+        // travelled, speed in cells per second and variation is how much each particle may
+        // randomly differ from that.
+        settings.insert("cursor_vfx_mode".to_string(), Setting::new_string("".to_string()));
+        settings.insert("cursor_vfx_particle_lifetime".to_string(), Setting::new_f32(1.2));
+        settings.insert("cursor_vfx_particle_density".to_string(), Setting::new_f32(7.0));
+        settings.insert("cursor_vfx_opacity".to_string(), Setting::new_f32(200.0));
+        settings.insert("cursor_vfx_particle_speed".to_string(), Setting::new_f32(10.0));
+        settings.insert("cursor_vfx_particle_variation".to_string(), Setting::new_f32(0.5));
+
+        Settings { neovim_arguments, server_address, settings: Mutex::new(settings) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn map_setting(entries: &[(&str, &str)]) -> Setting {
+        Setting::new_map(entries.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect())
+    }
+
+    #[test]
+    fn parsing_a_map_merges_into_the_current_entries() {
+        let mut setting = map_setting(&[("<C-=>", "zoom_in"), ("<C-->", "zoom_out")]);
+        setting.parse(Value::Map(vec![
+            (Value::from("<C-=>"), Value::from("")),
+            (Value::from("<F11>"), Value::from("toggle_fullscreen"))
+        ]));
+
+        let map = setting.read_map();
+        assert_eq!(map.len(), 3);
+        assert_eq!(map["<C-=>"], "");
+        assert_eq!(map["<C-->"], "zoom_out");
+        assert_eq!(map["<F11>"], "toggle_fullscreen");
+    }
+
+    #[test]
+    fn parsing_an_empty_map_keeps_every_entry() {
+        let mut setting = map_setting(&[("<C-=>", "zoom_in")]);
+        setting.parse(Value::Map(Vec::new()));
+        assert_eq!(setting.read_map(), map_setting(&[("<C-=>", "zoom_in")]).read_map());
+    }
+
+    #[test]
+    fn parsing_a_map_skips_entries_which_are_not_strings() {
+        let mut setting = map_setting(&[("<C-=>", "zoom_in")]);
+        setting.parse(Value::Map(vec![
+            (Value::from("<C-=>"), Value::from(1)),
+            (Value::from(2), Value::from("zoom_out"))
+        ]));
+        assert_eq!(setting.read_map(), map_setting(&[("<C-=>", "zoom_in")]).read_map());
+    }
 
-        Settings { neovim_arguments, settings: Mutex::new(settings) }
+    #[test]
+    fn parsing_something_other_than_a_map_changes_nothing() {
+        let mut setting = map_setting(&[("<C-=>", "zoom_in")]);
+        setting.parse(Value::from("zoom_out"));
+        assert_eq!(setting.read_map(), map_setting(&[("<C-=>", "zoom_in")]).read_map());
     }
 }
@@ -1,20 +1,33 @@
+use std::collections::HashMap;
+use std::process::Command;
 use std::time::{Duration, Instant};
-use std::thread::sleep;
 
 use log::{info, debug, error};
 use skulpin::{LogicalSize, PhysicalSize};
 use skulpin::sdl2;
-use skulpin::sdl2::event::Event;
-use skulpin::sdl2::keyboard::Mod;
+use skulpin::sdl2::event::{Event, WindowEvent};
+use skulpin::sdl2::clipboard::ClipboardUtil;
+use skulpin::sdl2::keyboard::Keycode;
+use skulpin::sdl2::mouse::MouseButton;
+use skulpin::sdl2::video::FullscreenType;
+use skulpin::skia_safe::{Point, Rect};
 use skulpin::{RendererBuilder, PresentMode, CoordinateSystem, dpis};
 
-use crate::bridge::{parse_keycode, append_modifiers, BRIDGE, UiCommand};
+use crate::bridge::{encode_key, KeyPairing, KeyPress, BRIDGE, UiCommand, ConnectionStatus, handle_clipboard_requests};
 use crate::renderer::Renderer;
 use crate::redraw_scheduler::REDRAW_SCHEDULER;
-use crate::editor::EDITOR;
+use crate::editor::{EDITOR, DEFAULT_GRID, RenderFrame, WindowKind};
+use crate::external_window::ExternalWindow;
+use crate::gui_keybindings::GuiAction;
 use crate::settings::SETTINGS;
+use crate::attention::{request_attention, clear_attention_request};
 use crate::INITIAL_DIMENSIONS;
 
+// Each zoom step scales the font by this much, within the limits below.
+const FONT_ZOOM_STEP: f32 = 1.1;
+const MINIMUM_FONT_SCALE: f32 = 0.25;
+const MAXIMUM_FONT_SCALE: f32 = 4.0;
+
 #[derive(RustEmbed)]
 #[folder = "assets/"]
 struct Asset;
@@ -28,15 +41,94 @@ fn windows_fix_dpi() {
     }
 }
 
+pub fn logical_mouse_position(x: i32, y: i32, window: &sdl2::video::Window) -> Point {
+    let position = LogicalSize::from_physical_size_tuple((x.max(0) as u32, y.max(0) as u32), window)
+        .expect("Could not calculate logical mouse position");
+    Point::new(position.width as f32, position.height as f32)
+}
+
+// Encodes a key press and sends it to neovim, unless it is bound to a gui action in which case
+// the action is returned for the ui loop to run instead.
+fn send_key(key_press: KeyPress) -> Option<GuiAction> {
+    let alt_is_meta = SETTINGS.get("alt_is_meta").read_bool();
+    let text = key_press.text.as_ref().map(String::as_str);
+    let key = encode_key(key_press.keycode, key_press.modifiers, text, alt_is_meta)?;
+    match GuiAction::bound_to(&key) {
+        // There is nothing to copy without a selection, so the key is left to neovim.
+        Some(GuiAction::Copy) if EDITOR.lock().copy_keys().is_none() => {},
+        Some(action) => return Some(action),
+        None => {}
+    }
+    BRIDGE.queue_command(UiCommand::Keyboard(key));
+    None
+}
+
+// Runs an action bound in g:neovide_keybindings. Zooming only changes font_scale, which the
+// caller hands to the renderers.
+fn run_gui_action(action: GuiAction, window: &mut sdl2::video::Window, clipboard: &ClipboardUtil, font_scale: &mut f32) {
+    match action {
+        GuiAction::ZoomIn => *font_scale = (*font_scale * FONT_ZOOM_STEP).min(MAXIMUM_FONT_SCALE),
+        GuiAction::ZoomOut => *font_scale = (*font_scale / FONT_ZOOM_STEP).max(MINIMUM_FONT_SCALE),
+        GuiAction::ZoomReset => *font_scale = 1.0,
+        GuiAction::ToggleFullscreen => {
+            let fullscreen = if window.fullscreen_state() == FullscreenType::Off {
+                FullscreenType::Desktop
+            } else {
+                FullscreenType::Off
+            };
+            if let Err(error) = window.set_fullscreen(fullscreen) {
+                error!("Could not toggle fullscreen: {}", error);
+            }
+        },
+        // The clipboard goes through nvim_paste instead of being typed out.
+        GuiAction::Paste => match clipboard.clipboard_text() {
+            Ok(text) => BRIDGE.queue_command(UiCommand::Paste(text)),
+            Err(error) => error!("Could not read the clipboard: {}", error)
+        },
+        // Yanking into the + register reaches the system clipboard through our provider.
+        GuiAction::Copy => if let Some(keys) = EDITOR.lock().copy_keys() {
+            BRIDGE.queue_command(UiCommand::Keyboard(keys.to_string()));
+        },
+        // A new window is another Neovide with a neovim of its own.
+        GuiAction::NewWindow => {
+            let spawned = std::env::current_exe()
+                .and_then(|executable| Command::new(executable).spawn());
+            if let Err(error) = spawned {
+                error!("Could not open a new window: {}", error);
+            }
+        }
+    }
+}
+
 fn handle_new_grid_size(new_size: LogicalSize, renderer: &Renderer) {
     if new_size.width > 0 && new_size.height > 0 {
         let new_width = ((new_size.width + 1) as f32 / renderer.font_width) as u32;
-        let new_height = ((new_size.height + 1) as f32 / renderer.font_height) as u32;
+        let new_height = ((new_size.height + 1) as f32 - renderer.tabline_height) / renderer.font_height;
+        let new_height = new_height.max(0.0) as u32;
         // Add 1 here to make sure resizing doesn't change the grid size on startup
         BRIDGE.queue_command(UiCommand::Resize { width: new_width, height: new_height });
     }
 }
 
+// Opens an OS window for every window neovim moved out with ext_multigrid and drops the ones
+// which were closed, hidden or moved back into the main window.
+fn update_external_windows(
+        external_windows: &mut HashMap<u64, ExternalWindow>, frame: &RenderFrame,
+        video_subsystem: &sdl2::VideoSubsystem, renderer: &Renderer) {
+    let is_external = |grid_id: u64| frame.windows.iter()
+        .any(|window| window.grid_id == grid_id && window.kind == WindowKind::External && !window.hidden);
+
+    external_windows.retain(|grid_id, _| is_external(*grid_id));
+
+    for window_frame in frame.windows.iter() {
+        if is_external(window_frame.grid_id) && !external_windows.contains_key(&window_frame.grid_id) {
+            if let Some(external_window) = ExternalWindow::new(video_subsystem, window_frame, renderer.font_width, renderer.font_height) {
+                external_windows.insert(window_frame.grid_id, external_window);
+            }
+        }
+    }
+}
+
 pub fn ui_loop() {
     let sdl_context = sdl2::init().expect("Failed to initialize sdl2");
     let video_subsystem = sdl_context.video().expect("Failed to create sdl video subsystem");
@@ -83,10 +175,22 @@ pub fn ui_loop() {
     info!("renderer created");
 
     let mut mouse_down = false;
-    let mut mouse_position = LogicalSize {
-        width: 0, 
-        height: 0
-    };
+    let mut mouse_position = Point::new(0.0, 0.0);
+    // Grid the mouse was pressed in along with the last cell reported to neovim.
+    let mut mouse_grid = (DEFAULT_GRID, (0, 0));
+
+    let mut external_windows: HashMap<u64, ExternalWindow> = HashMap::new();
+    let clipboard = video_subsystem.clipboard();
+    let mut focused = true;
+    // Whether any of our windows had focus as of the last frame. Moving between the main window
+    // and an external one shouldn't look like leaving neovim.
+    let mut previous_any_window_focused = true;
+    let mut attention_requested = false;
+    // Text an input method is composing, which neovim only hears about once it is committed.
+    let mut preedit = String::new();
+    let mut text_input_active = video_subsystem.text_input().is_active();
+    let mut previous_cursor_region = Rect::new_empty();
+    let mut font_scale = 1.0;
 
     let mut title = "Neovide".to_string();
     let mut previous_size = LogicalSize::new(&window).unwrap();
@@ -103,62 +207,148 @@ pub fn ui_loop() {
             window.set_title(&title).expect("Could not set title");
         }
 
-        let mut ignore_text_input = false;
+        handle_clipboard_requests(&clipboard);
+
+        // Input methods only get keys in modes where they type text. Elsewhere keys are commands
+        // and are encoded from their keycodes instead.
+        let accepts_text_input = EDITOR.lock().accepts_text_input();
+        if accepts_text_input != text_input_active {
+            text_input_active = accepts_text_input;
+            if text_input_active {
+                video_subsystem.text_input().start();
+            } else {
+                // Stopping text input cancels whatever was being composed, which a mapping or
+                // autocommand can do by leaving insert mode.
+                video_subsystem.text_input().stop();
+                if !preedit.is_empty() {
+                    preedit.clear();
+                    renderer.set_preedit(String::new());
+                    REDRAW_SCHEDULER.queue_next_frame();
+                }
+            }
+        }
+
+        let mut dropped_files = Vec::new();
+        let mut key_pairing = KeyPairing::new(text_input_active);
+        let mut gui_actions = Vec::new();
         for event in event_pump.poll_iter() {
+            match event {
+                Event::TextInput { .. } => {},
+                _ => if let Some(key_press) = key_pairing.flush() {
+                    gui_actions.extend(send_key(key_press));
+                }
+            }
+
+            if let Event::Window { win_event, window_id, .. } = &event {
+                if *window_id == window.id() {
+                    match win_event {
+                        WindowEvent::FocusGained => focused = true,
+                        WindowEvent::FocusLost => focused = false,
+                        _ => {}
+                    }
+                }
+            }
+
+            if let ConnectionStatus::Disconnected(_) = BRIDGE.connection_status() {
+                // Keep the last frame on screen and only listen for the reconnect and quit keys
+                // until neovim is back.
+                match event {
+                    Event::Quit {..} => break 'running,
+                    Event::Window { win_event: WindowEvent::Close, window_id, .. } if window_id == window.id() => break 'running,
+                    Event::Window {..} => REDRAW_SCHEDULER.queue_next_frame(),
+                    Event::KeyDown { keycode: Some(Keycode::Return), .. } |
+                    Event::KeyDown { keycode: Some(Keycode::KpEnter), .. } => {
+                        BRIDGE.reconnect();
+                        handle_new_grid_size(previous_size, &renderer);
+                        REDRAW_SCHEDULER.queue_next_frame();
+                    },
+                    Event::KeyDown { keycode: Some(Keycode::Q), .. } |
+                    Event::KeyDown { keycode: Some(Keycode::Escape), .. } => break 'running,
+                    _ => {}
+                }
+                continue;
+            }
+
+            // Mouse and window events belong to whichever window they were sent to. Keyboard
+            // input is handled the same way no matter which window has focus.
+            if let Some(window_id) = event.get_window_id().filter(|window_id| *window_id != window.id()) {
+                if let Some(external_window) = external_windows.values_mut().find(|external_window| external_window.window_id() == window_id) {
+                    if external_window.handle_event(&event) {
+                        REDRAW_SCHEDULER.queue_next_frame();
+                        continue;
+                    }
+                }
+            }
+
             match event {
                 Event::Quit {..} => break 'running,
+                // With more than one OS window open, closing the main one doesn't quit on its own.
+                Event::Window { win_event: WindowEvent::Close, window_id, .. } if window_id == window.id() => break 'running,
                 Event::Window {..} => REDRAW_SCHEDULER.queue_next_frame(),
+                // Keys pressed while composing belong to the input method.
+                Event::KeyDown { .. } if !preedit.is_empty() => {},
                 Event::KeyDown { keycode: Some(keycode), keymod: modifiers, .. } => {
-                    if let Some((key_text, special)) = parse_keycode(keycode) {
-                        let will_text_input =
-                            
-                            !modifiers.contains(Mod::LCTRLMOD) &&
-                            !modifiers.contains(Mod::RCTRLMOD) &&
-                            !modifiers.contains(Mod::LALTMOD) &&
-                            !modifiers.contains(Mod::RALTMOD) &&
-                            !modifiers.contains(Mod::LGUIMOD) &&
-                            !modifiers.contains(Mod::RGUIMOD);
-                        if will_text_input && !special {
-                            break;
-                        }
-
-                        BRIDGE.queue_command(UiCommand::Keyboard(append_modifiers(modifiers, key_text, special)));
-                        ignore_text_input = true;
+                    if let Some(key_press) = key_pairing.key_down(keycode, modifiers) {
+                        gui_actions.extend(send_key(key_press));
+                    }
+                },
+                Event::TextEditing { text, .. } => {
+                    if text != preedit {
+                        preedit = text;
+                        renderer.set_preedit(preedit.clone());
+                        REDRAW_SCHEDULER.queue_next_frame();
                     }
                 },
                 Event::TextInput { text, .. } => {
-                    if ignore_text_input {
-                        ignore_text_input = false;
-                    } else {
-                        let text = if text == "<" {
-                            String::from("<lt>")
-                        } else {
-                            text
-                        };
-                        BRIDGE.queue_command(UiCommand::Keyboard(text))
+                    if !preedit.is_empty() {
+                        preedit.clear();
+                        renderer.set_preedit(String::new());
+                        REDRAW_SCHEDULER.queue_next_frame();
                     }
+
+                    gui_actions.extend(send_key(key_pairing.text_input(text)));
                 },
                 Event::MouseMotion { x, y, .. } => {
-                    let previous_position = mouse_position;
-                    mouse_position = LogicalSize::from_physical_size_tuple((
-                            (x as f32 / renderer.font_width) as u32,
-                            (y as f32 / renderer.font_height) as u32
-                        ), 
-                        &window
-                    ).expect("Could not calculate logical mouse position");
-                    if mouse_down && previous_position != mouse_position {
-                        BRIDGE.queue_command(UiCommand::Drag(mouse_position.width, mouse_position.height));
+                    mouse_position = logical_mouse_position(x, y, &window);
+                    if mouse_down {
+                        let (grid_id, previous_position) = mouse_grid;
+                        let position = renderer.grid_position(grid_id, mouse_position);
+                        if previous_position != position {
+                            mouse_grid = (grid_id, position);
+                            BRIDGE.queue_command(UiCommand::Drag { grid_id, position });
+                        }
                     }
                 },
-                Event::MouseButtonDown { .. } => {
-                    BRIDGE.queue_command(UiCommand::MouseButton { action: String::from("press"), position: (mouse_position.width, mouse_position.height) });
-                    mouse_down = true;
+                Event::MouseButtonDown { mouse_btn, x, y, .. } => {
+                    mouse_position = logical_mouse_position(x, y, &window);
+                    if mouse_position.y < renderer.tabline_height {
+                        // Clicks on the tabline are handled here instead of being forwarded to
+                        // neovim since the grid doesn't know anything about it.
+                        if let Some(tab) = renderer.tab_at(mouse_position) {
+                            match mouse_btn {
+                                MouseButton::Left => BRIDGE.queue_command(UiCommand::SwitchTab(tab)),
+                                MouseButton::Middle => BRIDGE.queue_command(UiCommand::CloseTab(tab)),
+                                _ => {}
+                            }
+                        }
+                    } else {
+                        mouse_grid = renderer.grid_at(mouse_position);
+                        let (grid_id, position) = mouse_grid;
+                        BRIDGE.queue_command(UiCommand::MouseButton { action: String::from("press"), grid_id, position });
+                        mouse_down = true;
+                    }
                 },
-                Event::MouseButtonUp { .. } => {
-                    BRIDGE.queue_command(UiCommand::MouseButton { action: String::from("release"), position: (mouse_position.width, mouse_position.height) });
+                // Releases after a tabline click have no matching press in neovim.
+                Event::MouseButtonUp { .. } if mouse_down => {
+                    let (grid_id, position) = mouse_grid;
+                    BRIDGE.queue_command(UiCommand::MouseButton { action: String::from("release"), grid_id, position });
                     mouse_down = false;
                 },
+                Event::DropFile { filename, .. } => dropped_files.push(filename),
+                Event::DropText { filename, .. } => BRIDGE.queue_command(UiCommand::DropText(filename)),
                 Event::MouseWheel { x, y, .. } => {
+                    let (grid_id, position) = renderer.grid_at(mouse_position);
+
                     let vertical_input_type = if y > 0 {
                         Some("up")
                     } else if y < 0 {
@@ -168,7 +358,7 @@ pub fn ui_loop() {
                     };
 
                     if let Some(input_type) = vertical_input_type {
-                        BRIDGE.queue_command(UiCommand::Scroll { direction: input_type.to_string(), position: (mouse_position.width, mouse_position.height) });
+                        BRIDGE.queue_command(UiCommand::Scroll { direction: input_type.to_string(), grid_id, position });
                     }
 
                     let horizontal_input_type = if x > 0 {
@@ -180,13 +370,40 @@ pub fn ui_loop() {
                     };
 
                     if let Some(input_type) = horizontal_input_type {
-                        BRIDGE.queue_command(UiCommand::Scroll { direction: input_type.to_string(), position: (mouse_position.width, mouse_position.height) });
+                        BRIDGE.queue_command(UiCommand::Scroll { direction: input_type.to_string(), grid_id, position });
                     }
                 },
                 _ => {}
             }
         }
 
+        if let Some(key_press) = key_pairing.flush() {
+            gui_actions.extend(send_key(key_press));
+        }
+
+        // Actions run once the events are handled since they need the window to themselves.
+        for action in gui_actions {
+            run_gui_action(action, &mut window, &clipboard, &mut font_scale);
+            renderer.set_font_scale(font_scale);
+            REDRAW_SCHEDULER.queue_next_frame();
+        }
+
+        // Files dropped together arrive as separate events, but are opened as a batch.
+        if !dropped_files.is_empty() {
+            BRIDGE.queue_command(UiCommand::DropFiles(dropped_files));
+        }
+
+        let any_window_focused = focused || external_windows.values().any(ExternalWindow::is_focused);
+        if any_window_focused != previous_any_window_focused {
+            BRIDGE.queue_command(if any_window_focused { UiCommand::FocusGained } else { UiCommand::FocusLost });
+            previous_any_window_focused = any_window_focused;
+
+            if any_window_focused && attention_requested {
+                clear_attention_request(&window);
+                attention_requested = false;
+            }
+        }
+
         let new_size = LogicalSize::new(&window).unwrap();
         if previous_size != new_size {
             handle_new_grid_size(new_size, &renderer);
@@ -204,21 +421,44 @@ pub fn ui_loop() {
 
         debug!("Render Triggered");
         if REDRAW_SCHEDULER.should_draw() || SETTINGS.get("no_idle").read_bool() {
+            let frame = EDITOR.lock().take_render_frame();
+
+            if frame.request_attention && !any_window_focused {
+                request_attention(&window);
+                attention_requested = true;
+            }
+
+            update_external_windows(&mut external_windows, &frame, &video_subsystem, &renderer);
+            for external_window in external_windows.values_mut() {
+                external_window.set_font_scale(font_scale);
+                if !external_window.draw(&frame) {
+                    error!("Render failed for external window of grid {}", external_window.grid_id);
+                }
+            }
+
             if skulpin_renderer.draw(&window, |canvas, coordinate_system_helper| {
-                if renderer.draw(canvas, coordinate_system_helper) {
+                if renderer.draw(&frame, focused, canvas, coordinate_system_helper) {
                     handle_new_grid_size(new_size, &renderer)
                 }
             }).is_err() {
                 error!("Render failed. Closing");
                 break;
             }
+
+            // Input methods place their candidate popups next to this region.
+            if renderer.cursor_region != previous_cursor_region {
+                previous_cursor_region = renderer.cursor_region;
+                video_subsystem.text_input().set_rect(sdl2::rect::Rect::new(
+                    previous_cursor_region.left as i32, previous_cursor_region.top as i32,
+                    previous_cursor_region.width().max(1.0) as u32, previous_cursor_region.height().max(1.0) as u32));
+            }
         }
 
         let elapsed = frame_start.elapsed();
         let refresh_rate = SETTINGS.get("refresh_rate").read_u16() as f32;
         let frame_length = Duration::from_secs_f32(1.0 / refresh_rate);
         if elapsed < frame_length {
-            sleep(frame_length - elapsed);
+            REDRAW_SCHEDULER.sleep(frame_length - elapsed);
         }
     }
 }